@@ -2,38 +2,43 @@ use mursten::{Backend, Data, RenderChain, UpdateChain};
 
 use nalgebra::*;
 
-use shaders;
+use debug_draw::DebugDrawQueue;
+use device_selection::{self, DeviceSelection};
+use diagnostics;
+use error::VulkanBackendError;
+use light::{self, Attenuation, Light};
+use material::Material;
+use mesh::{self, ImmediateQueue, MeshHandle, MeshQueue, NormalMode, Winding};
+use overlay::OverlayQueue;
+use render_state::{DebugView, RenderState};
+use renderer::{Capture, Frame, Renderer};
+use shadow::ShadowFlags;
+use text::{FontHandle, FontStore, TextAlign};
+use texture::{TextureHandle, TextureOptions, TextureQueue};
+use screenshot::{self, CaptureRequest, PendingCapture};
 
+use image;
+use image::{ImageError, RgbaImage};
+
+use std::fs;
+use std::io;
 use std::mem;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use vulkano_win::required_extensions;
 use vulkano_win::VkSurfaceBuild;
 
-use vulkano::buffer::BufferUsage;
-use vulkano::buffer::CpuAccessibleBuffer;
-use vulkano::command_buffer::AutoCommandBufferBuilder;
-use vulkano::command_buffer::DynamicState;
-use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
-use vulkano::descriptor::PipelineLayoutAbstract;
 use vulkano::device::Device;
 use vulkano::device::DeviceExtensions;
-use vulkano::device::Queue;
 use vulkano::format::Format;
-use vulkano::framebuffer::Framebuffer;
-use vulkano::framebuffer::RenderPass;
-use vulkano::framebuffer::RenderPassAbstract;
-use vulkano::framebuffer::RenderPassDesc;
-use vulkano::framebuffer::Subpass;
+use vulkano::framebuffer::FramebufferAbstract;
 use vulkano::image::attachment::AttachmentImage;
 use vulkano::image::traits::ImageAccess;
 use vulkano::image::ImageUsage;
-use vulkano::image::SwapchainImage;
+use vulkano::instance::debug::DebugCallback;
 use vulkano::instance::Instance;
-use vulkano::instance::PhysicalDevice;
-use vulkano::pipeline::vertex::SingleBufferDefinition;
-use vulkano::pipeline::viewport::Viewport;
-use vulkano::pipeline::GraphicsPipeline;
+use vulkano::instance::InstanceExtensions;
 use vulkano::swapchain;
 use vulkano::swapchain::AcquireError;
 use vulkano::swapchain::PresentMode;
@@ -42,6 +47,7 @@ use vulkano::swapchain::SurfaceTransform;
 use vulkano::swapchain::Swapchain;
 use vulkano::swapchain::SwapchainCreationError;
 use vulkano::sync::now;
+use vulkano::sync::FlushError;
 use vulkano::sync::GpuFuture;
 
 use winit::CursorState;
@@ -50,24 +56,45 @@ use winit::EventsLoop;
 use winit::Window;
 use winit::WindowBuilder;
 use winit::WindowEvent;
+use winit::ElementState;
 use winit::KeyboardInput;
+use winit::VirtualKeyCode;
 
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
+#[allow(deprecated)]
 pub struct Uniforms {
     pub projection_view: Matrix4<f32>,
 
-    pub light_color: Vector4<f32>,
-    pub light_origin: Vector4<f32>,
+    /// Color of the light that reaches every surface. The other lights are added
+    /// each frame with `VulkanBackend::add_light`.
+    pub ambient_light_color: Vector4<f32>,
+    /// World space position of the viewer, for specular highlights.
+    pub camera_position: Vector4<f32>,
 
     pub ambient_light_strength: f32,
     pub diffuse_light_strength: f32,
     pub specular_light_strength: f32,
+
+    /// Distance shown as white by `DebugView::Depth`.
+    pub debug_depth_range: f32,
+
+    // Not read by the shaders. They go after everything that is, so the push
+    // constants stay the same.
+    /// Color of the persistent light. Changing it with `VulkanBackend::set_uniforms`
+    /// replaces the persistent light and the ambient color.
+    #[deprecated(note = "use `VulkanBackend::set_persistent_light` and `ambient_light_color`")]
+    pub light_color: Vector4<f32>,
+    /// Position of the persistent light. Changing it with `VulkanBackend::set_uniforms`
+    /// replaces the persistent light.
+    #[deprecated(note = "use `VulkanBackend::set_persistent_light`")]
+    pub light_origin: Vector4<f32>,
 }
 
 impl Default for Uniforms {
     #[cfg_attr(rustfmt, rustfmt_skip)]
+    #[allow(deprecated)]
     fn default() -> Self {
         Self {
             projection_view: Orthographic3::new(-1.0, 1.0, -1.0, 1.0, -900.0, 900.0).to_homogeneous() * Matrix4::new(
@@ -76,15 +103,27 @@ impl Default for Uniforms {
                 0.0, 0.0, 1.0, 0.0,
                 0.0, 0.0, 0.0, 1.0,
             ),
-            light_color: Vector4::new(1.0, 1.0, 1.0, 1.0),
-            light_origin: Vector4::new(3.0, 3.0, -3.0, 1.0),
+            ambient_light_color: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            camera_position: Vector4::new(0.0, 0.0, 0.0, 1.0),
             ambient_light_strength: 0.2,
             diffuse_light_strength: 0.7,
             specular_light_strength: 0.3,
+            debug_depth_range: 100.0,
+            light_color: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            light_origin: Vector4::new(3.0, 3.0, -3.0, 1.0),
         }
     }
 }
 
+/// A point light that doesn't fade, the only kind of light there used to be.
+fn legacy_light(origin: Vector4<f32>, color: Vector4<f32>) -> Light {
+    let position = Point3::from_homogeneous(origin).unwrap_or_else(Point3::origin);
+    Light::point(position, [color.x, color.y, color.z]).with_attenuation(Attenuation::none())
+}
+
+/// Color format of the offscreen target used in headless mode.
+const OFFSCREEN_FORMAT: Format = Format::R8G8B8A8Srgb;
+
 #[derive(Debug, Clone, Copy)]
 pub struct Vertex {
     pub position: [f32; 4],
@@ -94,37 +133,280 @@ pub struct Vertex {
 }
 impl_vertex!(Vertex, position, normal, color, texture);
 
-pub struct VulkanBackend {
-    vertex_queue: Vec<Vertex>,
-    event_queue: Vec<Event>,
+/// Per instance data of an instanced draw. It goes in a second vertex buffer binding
+/// that advances once per instance instead of once per vertex.
+#[derive(Debug, Clone, Copy)]
+pub struct InstanceData {
+    pub instance_model: [[f32; 4]; 4],
+    pub instance_normal: [[f32; 4]; 4],
+    pub instance_tint: [f32; 4],
+}
+impl_vertex!(InstanceData, instance_model, instance_normal, instance_tint);
 
-    mouse_position: (f64, f64),
+impl InstanceData {
+    pub fn new(model: Matrix4<f32>, tint: [f32; 4]) -> Self {
+        Self {
+            instance_model: model.into(),
+            instance_normal: mesh::normal_matrix(&model).into(),
+            instance_tint: tint,
+        }
+    }
+}
 
-    dimensions: (u32, u32),
-    constants: Uniforms,
+impl Default for InstanceData {
+    fn default() -> Self {
+        InstanceData::new(Matrix4::identity(), [1.0, 1.0, 1.0, 1.0])
+    }
+}
 
+/// How the window takes up the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowMode {
+    Windowed,
+    /// Exclusive fullscreen on the primary monitor.
+    Fullscreen,
+    /// A window without decorations covering the primary monitor.
+    Borderless,
+}
+
+#[derive(Debug, Clone)]
+struct Settings {
+    title: String,
+    dimensions: Option<(u32, u32)>,
+    resizable: bool,
+    window_mode: WindowMode,
+    present_mode: PresentMode,
+    clear_color: [f32; 4],
     enable_validation_layers: bool,
     desired_validation_layer: &'static str,
+    depth_format: Option<Format>,
+    headless: Option<(u32, u32)>,
+    max_frames: Option<u64>,
+    device_selection: DeviceSelection,
+    debug_view_key: Option<VirtualKeyCode>,
+    samples: u32,
+    reversed_z: bool,
 }
 
-impl VulkanBackend {
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            title: String::from("Mursten"),
+            dimensions: None,
+            resizable: true,
+            window_mode: WindowMode::Windowed,
+            present_mode: PresentMode::Fifo,
+            clear_color: [0.1, 0.1, 0.1, 1.0],
+            enable_validation_layers: false,
+            desired_validation_layer: "VK_LAYER_LUNARG_standard_validation",
+            depth_format: None,
+            headless: None,
+            max_frames: None,
+            device_selection: DeviceSelection::default(),
+            debug_view_key: None,
+            samples: 1,
+            reversed_z: false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct VulkanBackendBuilder {
+    settings: Settings,
+}
+
+impl VulkanBackendBuilder {
     pub fn new() -> Self {
         Self {
-            vertex_queue: Vec::new(),
+            settings: Settings::default(),
+        }
+    }
+
+    pub fn title<S: Into<String>>(mut self, title: S) -> Self {
+        self.settings.title = title.into();
+        self
+    }
+
+    /// Initial inner size of the window. When not set the platform decides.
+    pub fn dimensions(mut self, width: u32, height: u32) -> Self {
+        self.settings.dimensions = Some((width, height));
+        self
+    }
+
+    /// When not, the window keeps the size it's created with, requested or not.
+    pub fn resizable(mut self, resizable: bool) -> Self {
+        self.settings.resizable = resizable;
+        self
+    }
+
+    pub fn window_mode(mut self, window_mode: WindowMode) -> Self {
+        self.settings.window_mode = window_mode;
+        self
+    }
+
+    /// Falls back to `PresentMode::Fifo` when the surface doesn't support it.
+    pub fn present_mode(mut self, present_mode: PresentMode) -> Self {
+        self.settings.present_mode = present_mode;
+        self
+    }
+
+    pub fn clear_color(mut self, clear_color: [f32; 4]) -> Self {
+        self.settings.clear_color = clear_color;
+        self
+    }
+
+    pub fn validation_layers(mut self, enable: bool) -> Self {
+        self.settings.enable_validation_layers = enable;
+        self
+    }
+
+    /// Layer requested when validation layers are enabled.
+    pub fn validation_layer(mut self, layer: &'static str) -> Self {
+        self.settings.desired_validation_layer = layer;
+        self
+    }
+
+    /// Used as is instead of the best depth format the device supports.
+    pub fn depth_format(mut self, depth_format: Format) -> Self {
+        self.settings.depth_format = Some(depth_format);
+        self
+    }
+
+    /// Renders into an offscreen image of the given size instead of opening a window.
+    /// No surface or swapchain is created, so this works on machines without a display.
+    pub fn headless(mut self, width: u32, height: u32) -> Self {
+        self.settings.headless = Some((width, height));
+        self
+    }
+
+    /// Samples per pixel for multisample anti-aliasing. Lowered to the highest count the
+    /// device supports, 1 turns it off.
+    pub fn samples(mut self, samples: u32) -> Self {
+        self.settings.samples = samples;
+        self
+    }
+
+    /// Maps the near plane to depth 1 and the far plane to 0, which spreads the precision
+    /// of floating point depth formats evenly over the distance. The camera projection
+    /// has to give depth in that range itself, like `infinite_perspective` with
+    /// `reversed_z` does. OpenGL style ones like `Perspective3` don't work with it.
+    pub fn reversed_z(mut self, reversed_z: bool) -> Self {
+        self.settings.reversed_z = reversed_z;
+        self
+    }
+
+    /// Stops `run` after the given amount of frames.
+    pub fn max_frames(mut self, frames: u64) -> Self {
+        self.settings.max_frames = Some(frames);
+        self
+    }
+
+    /// How to pick the GPU. The `MURSTEN_VULKAN_DEVICE` environment variable takes
+    /// precedence over this.
+    pub fn device_selection(mut self, device_selection: DeviceSelection) -> Self {
+        self.settings.device_selection = device_selection;
+        self
+    }
+
+    /// Key that switches to the next `DebugView` while the game runs.
+    pub fn debug_view_key(mut self, key: VirtualKeyCode) -> Self {
+        self.settings.debug_view_key = Some(key);
+        self
+    }
+
+    #[allow(deprecated)]
+    pub fn build(self) -> VulkanBackend {
+        let constants = Uniforms::default();
+        VulkanBackend {
+            immediate: ImmediateQueue::default(),
+            meshes: MeshQueue::default(),
+            textures: TextureQueue::default(),
+            material: Material::default(),
+            render_state: RenderState::default(),
+            shadow_flags: ShadowFlags::default(),
+            shadow_distance: 100.0,
+            debug_view: DebugView::default(),
+            debug_lines: DebugDrawQueue::default(),
+            debug_depth_test: true,
+            overlay: OverlayQueue::default(),
+            fonts: FontStore::default(),
+            font: None,
+            text_align: TextAlign::default(),
+            lights: Vec::new(),
+            persistent_light: Some(legacy_light(constants.light_origin, constants.light_color)),
+            normal_mode: NormalMode::default(),
+            winding: Winding::default(),
             event_queue: Vec::new(),
             mouse_position: (0.0, 0.0),
-            dimensions: (0, 0),
-            constants: Uniforms::default(),
-            enable_validation_layers: false,
-            desired_validation_layer: "VK_LAYER_LUNARG_standard_validation",
+            dimensions: self.settings.headless.or(self.settings.dimensions).unwrap_or((0, 0)),
+            constants,
+            exit_requested: false,
+            capture_request: CaptureRequest::default(),
+            last_capture: None,
+            settings: self.settings,
         }
     }
+}
+
+/// Draws everything queued into it once per frame. Each draw keeps the material,
+/// render state and shadow flags set at the time it was queued, so changing them
+/// only affects what's queued afterwards.
+pub struct VulkanBackend {
+    immediate: ImmediateQueue,
+    meshes: MeshQueue,
+    textures: TextureQueue,
+    material: Material,
+    render_state: RenderState,
+    shadow_flags: ShadowFlags,
+    shadow_distance: f32,
+    debug_view: DebugView,
+    debug_lines: DebugDrawQueue,
+    debug_depth_test: bool,
+    overlay: OverlayQueue,
+    fonts: FontStore,
+    font: Option<FontHandle>,
+    text_align: TextAlign,
+    lights: Vec<Light>,
+    persistent_light: Option<Light>,
+    normal_mode: NormalMode,
+    winding: Winding,
+    event_queue: Vec<Event>,
+
+    mouse_position: (f64, f64),
+
+    dimensions: (u32, u32),
+    constants: Uniforms,
+
+    exit_requested: bool,
+
+    capture_request: CaptureRequest,
+    last_capture: Option<RgbaImage>,
+
+    settings: Settings,
+}
+
+impl VulkanBackend {
+    pub fn new() -> Self {
+        VulkanBackendBuilder::new().build()
+    }
+
+    pub fn builder() -> VulkanBackendBuilder {
+        VulkanBackendBuilder::new()
+    }
 
     pub fn screen_size(&self) -> (u32, u32) {
         self.dimensions
     }
 
-    pub fn set_uniforms(&mut self, uniforms: Uniforms) {
+    /// Changing the deprecated `light_color` or `light_origin` replaces the persistent
+    /// light, and the ambient color follows `light_color`, like before there were
+    /// other lights.
+    #[allow(deprecated)]
+    pub fn set_uniforms(&mut self, mut uniforms: Uniforms) {
+        if uniforms.light_color != self.constants.light_color || uniforms.light_origin != self.constants.light_origin {
+            self.persistent_light = Some(legacy_light(uniforms.light_origin, uniforms.light_color));
+            uniforms.ambient_light_color = uniforms.light_color;
+        }
         self.constants = uniforms;
     }
 
@@ -132,8 +414,296 @@ impl VulkanBackend {
         self.constants
     }
 
-    pub fn enqueue_vertexes(&mut self, mut vertexes: Vec<Vertex>) {
-        self.vertex_queue.append(&mut vertexes);
+    pub fn enqueue_vertexes(&mut self, vertexes: Vec<Vertex>) {
+        self.immediate.push(
+            vertexes,
+            Matrix4::identity(),
+            self.material,
+            self.render_state,
+            self.shadow_flags,
+        );
+    }
+
+    /// Like `enqueue_vertexes`, but the vertexes are transformed by `model` on the GPU.
+    pub fn enqueue_vertexes_with_model(&mut self, vertexes: Vec<Vertex>, model: Matrix4<f32>) {
+        self.immediate.push(vertexes, model, self.material, self.render_state, self.shadow_flags);
+    }
+
+    /// Keeps the geometry on the GPU so it can be drawn every frame with `draw_mesh`
+    /// without uploading it again. The upload happens at the start of the next frame.
+    ///
+    /// Every three vertexes make a triangle. Identical vertexes are welded together
+    /// and the mesh is drawn with an index buffer. Fails when the vertexes don't make
+    /// whole triangles or there are none.
+    pub fn upload_vertexes(&mut self, vertexes: Vec<Vertex>) -> Result<MeshHandle, VulkanBackendError> {
+        let (vertexes, indices) = mesh::weld(vertexes);
+        self.meshes.upload(vertexes, indices)
+    }
+
+    /// Like `upload_vertexes`, for geometry that is already indexed. Every three
+    /// indices make a triangle. Fails when they don't make whole triangles, there are
+    /// none or an index is past the end of `vertexes`.
+    pub fn upload_indexed_vertexes(
+        &mut self,
+        vertexes: Vec<Vertex>,
+        indices: Vec<u32>,
+    ) -> Result<MeshHandle, VulkanBackendError> {
+        self.meshes.upload(vertexes, indices)
+    }
+
+    /// Frees the GPU memory behind the handle. Drawing it afterwards draws nothing.
+    pub fn release_mesh(&mut self, mesh: MeshHandle) {
+        self.meshes.release(mesh);
+    }
+
+    /// Draws a retained mesh this frame. Positions and normals are transformed by
+    /// `model` on the GPU.
+    pub fn draw_mesh(&mut self, mesh: MeshHandle, model: Matrix4<f32>) {
+        self.meshes.draw(mesh, model, self.material, self.render_state, self.shadow_flags);
+    }
+
+    /// Draws a retained mesh once per instance in a single draw call.
+    pub fn draw_mesh_instanced(&mut self, mesh: MeshHandle, instances: Vec<InstanceData>) {
+        self.meshes
+            .draw_instanced(mesh, instances, self.material, self.render_state, self.shadow_flags);
+    }
+
+    /// Keeps the image on the GPU so it can be sampled by the following draws. The
+    /// upload happens at the start of the next frame.
+    pub fn create_texture(&mut self, image: RgbaImage, options: TextureOptions) -> TextureHandle {
+        self.textures.upload(image, options)
+    }
+
+    /// Decodes an image file with the `image` crate and uploads it like `create_texture`.
+    pub fn load_texture<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        options: TextureOptions,
+    ) -> Result<TextureHandle, ImageError> {
+        let image = image::open(path)?.to_rgba();
+        Ok(self.create_texture(image, options))
+    }
+
+    pub fn release_texture(&mut self, texture: TextureHandle) {
+        self.textures.release(texture);
+    }
+
+    /// Texture sampled by everything queued from now on, multiplied by the vertex
+    /// color. `None` goes back to plain vertex colors. Shorthand for changing the
+    /// texture of the current material.
+    pub fn bind_texture(&mut self, texture: Option<TextureHandle>) {
+        self.material.texture = texture;
+    }
+
+    /// Material used by everything queued from now on.
+    pub fn set_material(&mut self, material: Material) {
+        self.material = material;
+    }
+
+    pub fn get_material(&self) -> Material {
+        self.material
+    }
+
+    /// Culling, polygon mode and depth testing of everything queued from now on.
+    pub fn set_render_state(&mut self, render_state: RenderState) {
+        self.render_state = render_state;
+    }
+
+    pub fn get_render_state(&self) -> RenderState {
+        self.render_state
+    }
+
+    /// Whether everything queued from now on casts and receives shadows.
+    pub fn set_shadow_flags(&mut self, shadow_flags: ShadowFlags) {
+        self.shadow_flags = shadow_flags;
+    }
+
+    pub fn get_shadow_flags(&self) -> ShadowFlags {
+        self.shadow_flags
+    }
+
+    /// How far along the view directional lights cast shadows when the projection has
+    /// no far plane, like `infinite_perspective`. Otherwise they reach the far plane and
+    /// this is ignored. The shadow map is spread over this distance, so shorter ones
+    /// give sharper shadows.
+    pub fn set_shadow_distance(&mut self, shadow_distance: f32) {
+        self.shadow_distance = shadow_distance;
+    }
+
+    pub fn get_shadow_distance(&self) -> f32 {
+        self.shadow_distance
+    }
+
+    /// What the whole frame shows, for debugging. Takes effect on the next frame.
+    pub fn set_debug_view(&mut self, debug_view: DebugView) {
+        info!("Debug view: {:?}", debug_view);
+        self.debug_view = debug_view;
+    }
+
+    pub fn get_debug_view(&self) -> DebugView {
+        self.debug_view
+    }
+
+    /// Switches to the next debug view, see `DebugView::next`.
+    pub fn cycle_debug_view(&mut self) {
+        let next = self.debug_view.next();
+        self.set_debug_view(next);
+    }
+
+    /// Whether debug lines queued from now on are hidden by the geometry in front of
+    /// them. When not, they're drawn over everything.
+    pub fn set_debug_depth_test(&mut self, depth_test: bool) {
+        self.debug_depth_test = depth_test;
+    }
+
+    /// Draws a line this frame, after the rest of the scene.
+    pub fn debug_line(&mut self, from: Point3<f32>, to: Point3<f32>, color: [f32; 4]) {
+        self.debug_lines.line(from, to, color, self.debug_depth_test);
+    }
+
+    /// A line from `origin` to `origin + direction`.
+    pub fn debug_ray(&mut self, origin: Point3<f32>, direction: Vector3<f32>, color: [f32; 4]) {
+        self.debug_lines.line(origin, origin + direction, color, self.debug_depth_test);
+    }
+
+    /// The edges of an axis aligned box.
+    pub fn debug_aabb(&mut self, min: Point3<f32>, max: Point3<f32>, color: [f32; 4]) {
+        self.debug_lines.aabb(min, max, color, self.debug_depth_test);
+    }
+
+    pub fn debug_sphere(&mut self, center: Point3<f32>, radius: f32, color: [f32; 4]) {
+        self.debug_lines.sphere(center, radius, color, self.debug_depth_test);
+    }
+
+    /// A grid on the XZ plane, `size` units wide with `divisions` cells per side.
+    pub fn debug_grid(&mut self, center: Point3<f32>, size: f32, divisions: u32, color: [f32; 4]) {
+        self.debug_lines.grid(center, size, divisions, color, self.debug_depth_test);
+    }
+
+    /// Marks a point with three lines along the axes.
+    pub fn debug_cross(&mut self, position: Point3<f32>, size: f32, color: [f32; 4]) {
+        self.debug_lines.cross(position, size, color, self.debug_depth_test);
+    }
+
+    /// Draws a rectangle of plain color over the scene this frame. Positions and sizes
+    /// are in pixels, from the top left corner of `screen_size`.
+    pub fn overlay_rect(&mut self, position: (f32, f32), size: (f32, f32), color: [f32; 4]) {
+        self.overlay.quad(position, size, (0.0, 0.0), (1.0, 1.0), color, None);
+    }
+
+    /// Like `overlay_rect`, showing the whole texture multiplied by `tint`.
+    pub fn overlay_texture(&mut self, texture: TextureHandle, position: (f32, f32), size: (f32, f32), tint: [f32; 4]) {
+        self.overlay.quad(position, size, (0.0, 0.0), (1.0, 1.0), tint, Some(texture));
+    }
+
+    /// Like `overlay_texture`, showing only the part of the texture between `uv_min`
+    /// and `uv_max`. Useful for sprite sheets.
+    pub fn overlay_texture_region(
+        &mut self,
+        texture: TextureHandle,
+        position: (f32, f32),
+        size: (f32, f32),
+        uv_min: (f32, f32),
+        uv_max: (f32, f32),
+        tint: [f32; 4],
+    ) {
+        self.overlay.quad(position, size, uv_min, uv_max, tint, Some(texture));
+    }
+
+    /// Reads a TrueType font. The first font loaded is the one text is drawn with
+    /// until `set_font` is called.
+    pub fn load_font<P: AsRef<Path>>(&mut self, path: P) -> io::Result<FontHandle> {
+        let bytes = fs::read(path)?;
+        self.load_font_from_bytes(bytes)
+    }
+
+    /// Like `load_font`, for fonts that are already in memory, such as the ones
+    /// embedded with `include_bytes!`.
+    pub fn load_font_from_bytes(&mut self, bytes: Vec<u8>) -> io::Result<FontHandle> {
+        let font = self.fonts.load(bytes)?;
+        if self.font.is_none() {
+            self.font = Some(font);
+        }
+        Ok(font)
+    }
+
+    /// Frees the font and the textures its glyphs were drawn into.
+    pub fn release_font(&mut self, font: FontHandle) {
+        self.fonts.release(font, &mut self.textures);
+        if self.font == Some(font) {
+            self.font = None;
+        }
+    }
+
+    /// Font of the text drawn from now on.
+    pub fn set_font(&mut self, font: FontHandle) {
+        self.font = Some(font);
+    }
+
+    /// Alignment of the text drawn from now on.
+    pub fn set_text_align(&mut self, align: TextAlign) {
+        self.text_align = align;
+    }
+
+    /// Draws text over the scene this frame, through the overlay. `position` is in
+    /// pixels and its meaning depends on the alignment, `size` is the height of a
+    /// line in pixels.
+    pub fn draw_text(&mut self, position: (f32, f32), size: f32, color: [f32; 4], text: &str) {
+        let font = match self.font {
+            Some(font) => font,
+            None => {
+                warn!("Tried to draw {:?} without loading a font first", text);
+                return;
+            }
+        };
+        let align = self.text_align;
+        self.fonts.draw(
+            font,
+            &mut self.textures,
+            &mut self.overlay,
+            position,
+            size,
+            color,
+            align,
+            text,
+        );
+    }
+
+    /// How normals are obtained when meshes from other crates are converted, from
+    /// now on.
+    pub fn set_normal_mode(&mut self, normal_mode: NormalMode) {
+        self.normal_mode = normal_mode;
+    }
+
+    pub fn get_normal_mode(&self) -> NormalMode {
+        self.normal_mode
+    }
+
+    /// Winding of the front of the triangles of meshes from other crates. Decides
+    /// which way the generated normals point.
+    pub fn set_winding(&mut self, winding: Winding) {
+        self.winding = winding;
+    }
+
+    pub fn get_winding(&self) -> Winding {
+        self.winding
+    }
+
+    /// Lights the current frame. Like the geometry, lights have to be added again
+    /// every frame. Only the first `MAX_LIGHTS` of a frame are used.
+    pub fn add_light(&mut self, light: Light) {
+        if self.lights.len() >= light::MAX_LIGHTS {
+            debug!("Ignoring {:?}, there are already {} lights this frame", light, light::MAX_LIGHTS);
+            return;
+        }
+        self.lights.push(light);
+    }
+
+    /// A light that stays on every frame until it's replaced or cleared with `None`.
+    /// It comes before the ones added with `add_light`, so it's never the one ignored.
+    /// Until then it's a white point light at (3, 3, -3) that doesn't fade.
+    pub fn set_persistent_light(&mut self, light: Option<Light>) {
+        self.persistent_light = light;
     }
 
     pub fn get_events(&mut self) -> Vec<Event> {
@@ -143,86 +713,265 @@ impl VulkanBackend {
     pub fn get_mouse_position(&self) -> (f64, f64) {
         self.mouse_position
     }
+
+    /// Asks `run` to stop once the current frame is finished. The GPU is waited on
+    /// and the final data is handed back to the caller.
+    pub fn request_exit(&mut self) {
+        self.exit_requested = true;
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.settings.headless.is_some()
+    }
+
+    /// Reads back the color attachment at the end of the current frame. The result is
+    /// available through `take_capture` from the next frame on.
+    ///
+    /// This waits for the GPU to finish the frame, so don't call it every frame.
+    pub fn request_capture(&mut self) {
+        self.capture_request.keep_image = true;
+    }
+
+    pub fn take_capture(&mut self) -> Option<RgbaImage> {
+        self.last_capture.take()
+    }
+
+    /// Saves the current frame as a PNG once it has been drawn.
+    pub fn save_screenshot<P: Into<PathBuf>>(&mut self, path: P) {
+        self.capture_request.save_to.push(path.into());
+    }
 }
 
-impl<D> Backend<D> for VulkanBackend
-where
-    D: Data,
-{
-    fn run(
-        mut self,
-        mut update_chain: UpdateChain<Self, D>,
-        mut render_chain: RenderChain<Self, D>,
-        mut data: D,
-    ) -> D {
-        let instance = {
-            let required_extensions = {
-                use vulkano::instance::InstanceExtensions;
-
-                let required = required_extensions();
-                println!("Required extensions: {:?}", required); // Change this to trace!
-                let supported = InstanceExtensions::supported_by_core().unwrap();
-                println!("Supported extensions: {:?}", supported); // Change this to trace!
-                let in_common = supported.intersection(&required);
-                if required != in_common {
-                    let missing = supported.difference(&required);
-                    panic!("Missing extensions: {:?}", missing);
+impl VulkanBackend {
+    /// Creates the instance, along with the callback that forwards validation messages
+    /// when validation layers are enabled. The callback must outlive every other object.
+    fn create_instance(
+        &self,
+        required: InstanceExtensions,
+    ) -> Result<(Arc<Instance>, Option<DebugCallback>), VulkanBackendError> {
+        let supported = InstanceExtensions::supported_by_core()?;
+        let required_extensions = {
+            debug!("Required extensions: {:?}", required);
+            trace!("Supported extensions: {:?}", supported);
+            let in_common = supported.intersection(&required);
+            if required != in_common {
+                let missing = required.difference(&supported);
+                return Err(VulkanBackendError::MissingExtensions(missing));
+            }
+            let mut required = required;
+            if self.settings.enable_validation_layers {
+                if supported.ext_debug_report {
+                    required.ext_debug_report = true;
+                } else {
+                    warn!("ext_debug_report isn't supported, validation messages won't be logged");
                 }
-                required
-            };
+            }
+            required
+        };
 
-            let validation_layers = {
-                use vulkano::instance::layers_list;
-                use vulkano::instance::LayerProperties;
-
-                if self.enable_validation_layers {
-                    let mut layers: Vec<LayerProperties> = layers_list().unwrap().collect();
-                    println!("There are {} validation layers available:", layers.len());
-                    for layer in layers.iter() {
-                        println!(
-                            "  Layer: {}, Description: {}",
-                            layer.name(),
-                            layer.description()
-                        );
-                    }
+        let validation_layers = {
+            use vulkano::instance::layers_list;
+            use vulkano::instance::LayerProperties;
 
-                    if layers
-                        .iter()
-                        .all(|layer| layer.name() != self.desired_validation_layer)
-                    {
-                        panic!("The layer {} is not listed. Remember that validation layers are not available for Mac yet.", self.desired_validation_layer);
-                    }
-                    vec![&self.desired_validation_layer]
-                } else {
-                    vec![]
+            if self.settings.enable_validation_layers {
+                let layers: Vec<LayerProperties> = layers_list()?.collect();
+                debug!("There are {} validation layers available:", layers.len());
+                for layer in layers.iter() {
+                    debug!(
+                        "  Layer: {}, Description: {}",
+                        layer.name(),
+                        layer.description()
+                    );
                 }
-            };
 
-            Instance::new(None, &required_extensions, validation_layers.into_iter())
-                .expect("failed to create Vulkan instance")
+                if layers
+                    .iter()
+                    .all(|layer| layer.name() != self.settings.desired_validation_layer)
+                {
+                    return Err(VulkanBackendError::MissingValidationLayer(
+                        self.settings.desired_validation_layer.to_owned(),
+                    ));
+                }
+                vec![&self.settings.desired_validation_layer]
+            } else {
+                vec![]
+            }
         };
 
-        let mut physical_devices = PhysicalDevice::enumerate(&instance);
-        let physical = physical_devices.next().expect("no device available");
+        let instance = Instance::new(None, &required_extensions, validation_layers.into_iter())?;
+        let debug_callback = if required_extensions.ext_debug_report {
+            diagnostics::install_debug_callback(&instance)
+        } else {
+            None
+        };
+        Ok((instance, debug_callback))
+    }
+
+    fn begin_capture(
+        &mut self,
+        renderer: &Renderer,
+        dimensions: [u32; 2],
+        supported: bool,
+    ) -> Result<Option<PendingCapture>, VulkanBackendError> {
+        let request = mem::replace(&mut self.capture_request, CaptureRequest::default());
+        if request.is_empty() {
+            return Ok(None);
+        }
+        if !supported {
+            warn!("Frame capture isn't supported by the current color attachment");
+            return Ok(None);
+        }
+        Ok(Some(PendingCapture {
+            request,
+            buffer: renderer.capture_buffer(dimensions)?,
+        }))
+    }
+
+    fn finish_capture(
+        &mut self,
+        pending: PendingCapture,
+        format: Format,
+        dimensions: [u32; 2],
+    ) -> Result<(), VulkanBackendError> {
+        let PendingCapture { request, buffer } = pending;
+        let image = {
+            let pixels = buffer.read()?;
+            match screenshot::to_rgba_image(format, dimensions, &pixels) {
+                Some(image) => image,
+                None => return Ok(()),
+            }
+        };
+
+        for path in request.save_to {
+            match screenshot::save_png(&image, &path) {
+                Ok(()) => info!("Saved screenshot to {}", path.display()),
+                Err(err) => error!("Couldn't save screenshot to {}: {}", path.display(), err),
+            }
+        }
+        if request.keep_image {
+            self.last_capture = Some(image);
+        }
+        Ok(())
+    }
+
+    fn frame(&mut self) -> Frame {
+        if let Some(light) = self.persistent_light {
+            self.lights.insert(0, light);
+            self.lights.truncate(light::MAX_LIGHTS);
+        }
+        Frame {
+            clear_color: self.settings.clear_color,
+            constants: self.constants,
+            immediate: &mut self.immediate,
+            meshes: &mut self.meshes,
+            lights: &mut self.lights,
+            shadow_distance: self.shadow_distance,
+            debug_view: self.debug_view,
+            debug_lines: &mut self.debug_lines,
+            overlay: &mut self.overlay,
+        }
+    }
+
+    /// Drops whatever was queued for a frame that won't be drawn.
+    fn discard_frame(&mut self) {
+        self.immediate.clear();
+        self.meshes.clear_draws();
+        self.lights.clear();
+        self.debug_lines.clear();
+        self.overlay.clear();
+    }
+
+    /// Hands the window events to the next update, and handles the ones the backend
+    /// reacts to itself. Returns whether the window was resized.
+    fn poll_events(&mut self, events_loop: &mut EventsLoop) -> bool {
+        self.event_queue.clear();
+        let mut resized = false;
+        events_loop.poll_events(|ev| {
+            //eprintln!("{:?}", ev);
+            self.event_queue.push(ev.clone());
+            match ev {
+                Event::WindowEvent { event, .. } => {
+                    match event {
+                        WindowEvent::Closed => self.exit_requested = true,
+                        WindowEvent::Resized(_, _) => resized = true,
+                        WindowEvent::CursorMoved { position, .. } => {
+                            self.mouse_position = position.into();
+                        },
+                        WindowEvent::KeyboardInput {
+                            input: KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(key),
+                                ..
+                            },
+                            ..
+                        } if Some(key) == self.settings.debug_view_key => self.cycle_debug_view(),
+                        _ => (),
+                    }
+                },
+                _ => (),
+            }
+        });
+        resized
+    }
+
+    fn frame_limit_reached(&self, frames: u64) -> bool {
+        self.exit_requested || self.settings.max_frames.map_or(false, |max| frames >= max)
+    }
+
+    fn run_windowed<D: Data>(
+        mut self,
+        mut update_chain: UpdateChain<Self, D>,
+        mut render_chain: RenderChain<Self, D>,
+        mut data: D,
+    ) -> Result<D, VulkanBackendError> {
+        let (instance, debug_callback) = self.create_instance(required_extensions())?;
 
         let mut events_loop = EventsLoop::new();
-        let window = WindowBuilder::new()
-            .build_vk_surface(&events_loop, instance.clone())
-            .unwrap();
+        let window = {
+            let mut builder = WindowBuilder::new().with_title(self.settings.title.clone());
+
+            let monitor = events_loop.get_primary_monitor();
+            let dimensions = match self.settings.window_mode {
+                WindowMode::Borderless => Some(monitor.get_dimensions()),
+                _ => self.settings.dimensions,
+            };
+            if let Some((width, height)) = dimensions {
+                builder = builder.with_dimensions(width, height);
+            }
+            builder = match self.settings.window_mode {
+                WindowMode::Windowed => builder,
+                WindowMode::Fullscreen => builder.with_fullscreen(Some(monitor.clone())),
+                WindowMode::Borderless => builder.with_decorations(false),
+            };
+
+            let window = builder.build_vk_surface(&events_loop, instance.clone())?;
+            if self.settings.window_mode == WindowMode::Borderless {
+                let (x, y) = monitor.get_position();
+                window.window().set_position(x, y);
+            }
+            // Pinned to the size the window got, whether it was requested or not.
+            if !self.settings.resizable {
+                let size = window.window().get_inner_size().ok_or(VulkanBackendError::WindowClosed)?;
+                window.window().set_min_dimensions(Some(size));
+                window.window().set_max_dimensions(Some(size));
+            }
+            window
+        };
 
         //window.window().set_cursor_state(CursorState::Grab);
         //window.window().set_fullscreen(Some(window.window().get_current_monitor()));
 
         let mut dimensions = {
-            let (width, height) = window.window().get_inner_size().unwrap().into();
+            let (width, height) = window.window().get_inner_size().ok_or(VulkanBackendError::WindowClosed)?.into();
             self.dimensions = (width, height);
             [width, height]
         };
 
-        let queue_family = physical
-            .queue_families()
-            .find(|&qf| qf.supports_graphics() && window.is_supported(qf).unwrap_or(false))
-            .expect("couldn't find a graphical queue family");
+        let (physical, queue_family) = device_selection::select_device(
+            &instance,
+            &self.settings.device_selection,
+            |qf| qf.supports_graphics() && window.is_supported(qf).unwrap_or(false),
+        )?;
 
         let (device, mut queues) = {
             let device_ext = DeviceExtensions {
@@ -237,19 +986,29 @@ where
                 physical.supported_features(),
                 &device_ext,
                 [(queue_family, 0.5)].iter().cloned(),
-            ).expect("failed to create device")
+            )?
         };
-        let queue = queues.next().unwrap();
+        let queue = queues.next().ok_or(VulkanBackendError::NoGraphicsQueueFamily)?;
 
+        let mut capture_supported = false;
         let (mut swapchain, mut images) = {
-            let caps = window
-                .capabilities(physical)
-                .expect("failed to get surface capabilities");
-            let alpha = caps.supported_composite_alpha.iter().next().unwrap();
+            let caps = window.capabilities(physical)?;
+            let alpha = caps
+                .supported_composite_alpha
+                .iter()
+                .next()
+                .ok_or(VulkanBackendError::NoCompositeAlpha)?;
             dimensions = caps.current_extent.unwrap_or(dimensions);
             self.dimensions = (dimensions[0], dimensions[1]);
 
             let format = caps.supported_formats[0].0;
+            capture_supported =
+                caps.supported_usage_flags.transfer_source && screenshot::is_supported(format);
+            let present_mode = if caps.present_modes.supports(self.settings.present_mode) {
+                self.settings.present_mode
+            } else {
+                PresentMode::Fifo
+            };
             Swapchain::new(
                 device.clone(),
                 window.clone(),
@@ -261,81 +1020,42 @@ where
                 &queue,
                 SurfaceTransform::Identity,
                 alpha,
-                PresentMode::Fifo,
+                present_mode,
                 true,
                 None,
-            ).expect("failed to create swapchain")
+            )?
         };
 
-        let vs = shaders::vs::Shader::load(device.clone()).expect("failed to create shader module");
-        let fs = shaders::fs::Shader::load(device.clone()).expect("failed to create shader module");
-
         //eprintln!("swapchain format {:?}", swapchain.format());
 
-        let render_pass = Arc::new(
-            single_pass_renderpass!(device.clone(),
-            attachments: {
-                color: {
-                    load: Clear,
-                    store: Store,
-                    format: swapchain.format(),
-                    samples: 1,
-                },
-                 depth: {
-                    load: Clear,
-                    store: DontCare,
-                    format: Format::D16Unorm,
-                    samples: 1,
-                }
-            },
-            pass: {
-                color: [color],
-                depth_stencil: {depth}
-            }
-        ).unwrap(),
-        );
-
-        let pipeline = Arc::new(
-            GraphicsPipeline::start()
-                .vertex_input_single_buffer()
-                .vertex_shader(vs.main_entry_point(), ())
-                .triangle_list()
-                .viewports_dynamic_scissors_irrelevant(1)
-                //.cull_mode_back()
-                .depth_stencil_simple_depth()
-                .fragment_shader(fs.main_entry_point(), ())
-                .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
-                .blend_alpha_blending()
-                .build(device.clone())
-                .unwrap(),
-        );
-
-        // let descriptor_set = Arc::new(PersistentDescriptorSet::start(pipeline.clone(), 0)
-        //     .add_buffer(data_buffer.clone()).unwrap()
-        //     .build().unwrap()
-        // );
+        let mut renderer = Renderer::new(
+            device.clone(),
+            queue.clone(),
+            swapchain.format(),
+            self.settings.depth_format,
+            self.settings.samples,
+            self.settings.reversed_z,
+        )?;
 
-        let mut framebuffers: Option<Vec<Arc<Framebuffer<_, _>>>> = None;
+        // Empty until they're built for the current swapchain images.
+        let mut framebuffers: Vec<Arc<FramebufferAbstract + Send + Sync>> = Vec::new();
         let mut previous_frame_end = Box::new(now(device.clone())) as Box<GpuFuture>;
         let mut recreate_swapchain = false;
+        let mut frames = 0;
 
-        loop {
+        while !self.frame_limit_reached(frames) {
             update_chain.update(&mut self, &mut data);
             render_chain.render(&mut self, &data);
 
             previous_frame_end.cleanup_finished();
 
-            let vertex_buffer = {
-                CpuAccessibleBuffer::from_iter(
-                    device.clone(),
-                    BufferUsage::all(),
-                    self.vertex_queue.drain(..),
-                ).expect("failed to create buffer")
-            };
+            self.fonts.upload_atlases(&mut self.textures);
+            previous_frame_end = renderer.sync_resources(&mut self.meshes, &mut self.textures, previous_frame_end)?;
 
             if recreate_swapchain {
                 dimensions = {
-                    let (new_width, new_height) = window.window().get_inner_size().unwrap().into();
+                    let (new_width, new_height) =
+                        window.window().get_inner_size().ok_or(VulkanBackendError::WindowClosed)?.into();
                     self.dimensions = (new_width, new_height);
                     [new_width, new_height]
                 };
@@ -343,51 +1063,32 @@ where
                 let (new_swapchain, new_images) =
                     match swapchain.recreate_with_dimension(dimensions) {
                         Ok(r) => r,
+                        // Happens while the window is minimized. Events still have to be
+                        // polled, or it would never be restored or closed.
                         Err(SwapchainCreationError::UnsupportedDimensions) => {
+                            self.discard_frame();
+                            self.poll_events(&mut events_loop);
                             continue;
                         }
-                        Err(err) => panic!("{:?}", err),
+                        Err(err) => return Err(err.into()),
                     };
 
                 mem::replace(&mut swapchain, new_swapchain);
                 mem::replace(&mut images, new_images);
 
-                framebuffers = None;
+                framebuffers.clear();
 
                 recreate_swapchain = false;
             }
 
-            if framebuffers.is_none() {
-                let new_framebuffers = Some(
-                    images
-                        .iter()
-                        .map(|image| {
-                            let attachment_usage = ImageUsage {
-                                transient_attachment: true,
-                                input_attachment: false,
-                                ..ImageUsage::none()
-                            };
-                            let img_dims = ImageAccess::dimensions(&image).width_height();
-                            let depth_buffer = AttachmentImage::with_usage(
-                                queue.device().clone(),
-                                img_dims,
-                                Format::D16Unorm,
-                                attachment_usage,
-                            ).unwrap();
-
-                            Arc::new(
-                                Framebuffer::start(render_pass.clone())
-                                    .add(image.clone())
-                                    .unwrap()
-                                    .add(depth_buffer.clone())
-                                    .unwrap()
-                                    .build()
-                                    .unwrap(),
-                            )
-                        })
-                        .collect::<Vec<_>>(),
-                );
-                mem::replace(&mut framebuffers, new_framebuffers);
+            if framebuffers.is_empty() {
+                framebuffers = images
+                    .iter()
+                    .map(|image| {
+                        let img_dims = ImageAccess::dimensions(&image).width_height();
+                        renderer.framebuffer(image.clone(), img_dims)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
             }
 
             let (image_num, acquire_future) =
@@ -395,79 +1096,207 @@ where
                     Ok(r) => r,
                     Err(AcquireError::OutOfDate) => {
                         recreate_swapchain = true;
+                        self.discard_frame();
+                        self.poll_events(&mut events_loop);
                         continue;
                     }
-                    Err(err) => panic!("{:?}", err),
+                    Err(err) => return Err(err.into()),
                 };
 
-            //eprintln!(" constants: {:?}", self.constants);
-            let dynamic_state = DynamicState {
-                line_width: None,
-                viewports: Some(vec![Viewport {
-                    origin: [0.0, 0.0],
-                    dimensions: [dimensions[0] as f32, dimensions[1] as f32],
-                    depth_range: 0.0..1.0,
-                }]),
-                scissors: None,
-            };
+            let pending_capture = self.begin_capture(&renderer, dimensions, capture_supported)?;
+            let capture = pending_capture.as_ref().map(|pending| Capture {
+                image: images[image_num].clone(),
+                buffer: pending.buffer.clone(),
+            });
 
-            let command_buffer =
-                AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family())
-                    .unwrap()
-                    .begin_render_pass(
-                        framebuffers.as_ref().unwrap()[image_num].clone(),
-                        false,
-                        vec![[0.1, 0.1, 0.1, 1.0].into(), 1.0f32.into()],
-                    )
-                    .unwrap()
-                    .draw(
-                        pipeline.clone(),
-                        dynamic_state,
-                        vertex_buffer.clone(),
-                        (),
-                        self.constants,
-                    )
-                    .unwrap()
-                    .end_render_pass()
-                    .unwrap()
-                    .build()
-                    .unwrap();
+            //eprintln!(" constants: {:?}", self.constants);
+            let command_buffer = renderer.draw(
+                framebuffers[image_num].clone(),
+                dimensions,
+                self.frame(),
+                capture,
+            )?;
 
             let future = previous_frame_end
                 .join(acquire_future)
-                .then_execute(queue.clone(), command_buffer)
-                .unwrap()
+                .then_execute(queue.clone(), command_buffer)?
                 .then_swapchain_present(queue.clone(), swapchain.clone(), image_num)
-                .then_signal_fence_and_flush()
-                .unwrap();
+                .then_signal_fence_and_flush();
+            previous_frame_end = match future {
+                Ok(future) => {
+                    if let Some(pending) = pending_capture {
+                        future.wait(None)?;
+                        self.finish_capture(pending, swapchain.format(), dimensions)?;
+                    }
+                    Box::new(future) as Box<_>
+                }
+                Err(FlushError::OutOfDate) => {
+                    // The frame was never presented, so its capture is taken from the
+                    // next one instead.
+                    if let Some(pending) = pending_capture {
+                        self.capture_request.retry(pending.request);
+                    }
+                    recreate_swapchain = true;
+                    Box::new(now(device.clone())) as Box<_>
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            frames += 1;
+            if self.poll_events(&mut events_loop) {
+                recreate_swapchain = true;
+            }
+        }
+
+        wait_for_gpu(previous_frame_end);
+
+        // Everything that lives on the device goes before the swapchain, and the
+        // swapchain goes before the surface. The instance is the last thing to go.
+        drop(framebuffers);
+        drop(renderer);
+        drop(images);
+        drop(swapchain);
+        drop(queue);
+        drop(device);
+        drop(window);
+        drop(debug_callback);
+
+        Ok(data)
+    }
+
+    fn run_headless<D: Data>(
+        mut self,
+        mut update_chain: UpdateChain<Self, D>,
+        mut render_chain: RenderChain<Self, D>,
+        mut data: D,
+        (width, height): (u32, u32),
+    ) -> Result<D, VulkanBackendError> {
+        let (instance, debug_callback) = self.create_instance(InstanceExtensions::none())?;
+
+        let (physical, queue_family) = device_selection::select_device(
+            &instance,
+            &self.settings.device_selection,
+            |qf| qf.supports_graphics(),
+        )?;
+
+        let (device, mut queues) = Device::new(
+            physical,
+            physical.supported_features(),
+            &DeviceExtensions::none(),
+            [(queue_family, 0.5)].iter().cloned(),
+        )?;
+        let queue = queues.next().ok_or(VulkanBackendError::NoGraphicsQueueFamily)?;
+
+        let dimensions = [width, height];
+        self.dimensions = (width, height);
+
+        let mut renderer = Renderer::new(
+            device.clone(),
+            queue.clone(),
+            OFFSCREEN_FORMAT,
+            self.settings.depth_format,
+            self.settings.samples,
+            self.settings.reversed_z,
+        )?;
+
+        let color_usage = ImageUsage {
+            color_attachment: true,
+            transfer_source: true,
+            ..ImageUsage::none()
+        };
+        let color_buffer =
+            AttachmentImage::with_usage(device.clone(), dimensions, OFFSCREEN_FORMAT, color_usage)?;
+        let framebuffer = renderer.framebuffer(color_buffer.clone(), dimensions)?;
+
+        let mut previous_frame_end = Box::new(now(device.clone())) as Box<GpuFuture>;
+        let mut frames = 0;
+
+        while !self.frame_limit_reached(frames) {
+            update_chain.update(&mut self, &mut data);
+            render_chain.render(&mut self, &data);
+
+            previous_frame_end.cleanup_finished();
+
+            self.fonts.upload_atlases(&mut self.textures);
+            previous_frame_end = renderer.sync_resources(&mut self.meshes, &mut self.textures, previous_frame_end)?;
+
+            let pending_capture = self.begin_capture(&renderer, dimensions, true)?;
+            let capture = pending_capture.as_ref().map(|pending| Capture {
+                image: color_buffer.clone(),
+                buffer: pending.buffer.clone(),
+            });
+
+            let command_buffer = renderer.draw(framebuffer.clone(), dimensions, self.frame(), capture)?;
+
+            let future = previous_frame_end
+                .then_execute(queue.clone(), command_buffer)?
+                .then_signal_fence_and_flush()?;
+            if let Some(pending) = pending_capture {
+                future.wait(None)?;
+                self.finish_capture(pending, OFFSCREEN_FORMAT, dimensions)?;
+            }
             previous_frame_end = Box::new(future) as Box<_>;
 
             self.event_queue.clear();
+            frames += 1;
+        }
 
-            let mut done = false;
-            events_loop.poll_events(|ev| {
-                //eprintln!("{:?}", ev);
-                self.event_queue.push(ev.clone());
-                match ev {
-                    Event::WindowEvent { event, .. } => {
-                        match event {
-                            WindowEvent::Closed => done = true,
-                            WindowEvent::Resized(_, _) => recreate_swapchain = true,
-                            WindowEvent::CursorMoved { position, .. } => {
-                                self.mouse_position = position.into();
-                            },
-                            _ => (),
-                        }
-                    },
-                    _ => (),
-                }
-            });
+        wait_for_gpu(previous_frame_end);
+
+        drop(framebuffer);
+        drop(color_buffer);
+        drop(renderer);
+        drop(queue);
+        drop(device);
+        drop(debug_callback);
+
+        Ok(data)
+    }
+}
+
+impl VulkanBackend {
+    /// Like `Backend::run`, but setup and frame submission failures are returned
+    /// instead of panicking.
+    pub fn try_run<D: Data>(
+        self,
+        update_chain: UpdateChain<Self, D>,
+        render_chain: RenderChain<Self, D>,
+        data: D,
+    ) -> Result<D, VulkanBackendError> {
+        match self.settings.headless {
+            Some(dimensions) => self.run_headless(update_chain, render_chain, data, dimensions),
+            None => self.run_windowed(update_chain, render_chain, data),
         }
+    }
+}
 
-        data
+impl<D> Backend<D> for VulkanBackend
+where
+    D: Data,
+{
+    fn run(
+        self,
+        update_chain: UpdateChain<Self, D>,
+        render_chain: RenderChain<Self, D>,
+        data: D,
+    ) -> D {
+        match self.try_run(update_chain, render_chain, data) {
+            Ok(data) => data,
+            Err(err) => panic!("{}", err),
+        }
     }
 
     fn quit(&mut self) {
-        panic!("A delicate exit");
+        self.request_exit();
+    }
+}
+
+/// Blocks until every command submitted so far has finished executing.
+fn wait_for_gpu(future: Box<GpuFuture>) {
+    match future.then_signal_fence_and_flush() {
+        Ok(fence) => if let Err(err) = fence.wait(None) {
+            error!("Failed waiting for the GPU to finish: {:?}", err);
+        },
+        Err(err) => error!("Failed waiting for the GPU to finish: {:?}", err),
     }
 }
@@ -0,0 +1,285 @@
+use error::VulkanBackendError;
+
+use nalgebra::*;
+
+use shaders;
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use vulkano::device::Device;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::pipeline::depth_stencil::DepthStencil;
+use vulkano::pipeline::vertex::SingleBufferDefinition;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+
+// Segments of each circle of a debug sphere.
+const SPHERE_SEGMENTS: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DebugVertex {
+    pub position: [f32; 4],
+    pub color: [f32; 4],
+}
+impl_vertex!(DebugVertex, position, color);
+
+/// Push constants of the debug line pipelines.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct DebugConstants {
+    pub projection_view: Matrix4<f32>,
+}
+
+/// Lines queued during the render chain, drawn on top of the frame and then forgotten.
+/// Every two vertexes make a line.
+#[derive(Default)]
+pub struct DebugDrawQueue {
+    pub depth_tested: Vec<DebugVertex>,
+    pub on_top: Vec<DebugVertex>,
+}
+
+impl DebugDrawQueue {
+    pub fn line(&mut self, from: Point3<f32>, to: Point3<f32>, color: [f32; 4], depth_test: bool) {
+        let vertexes = if depth_test {
+            &mut self.depth_tested
+        } else {
+            &mut self.on_top
+        };
+        vertexes.push(DebugVertex {
+            position: from.to_homogeneous().into(),
+            color,
+        });
+        vertexes.push(DebugVertex {
+            position: to.to_homogeneous().into(),
+            color,
+        });
+    }
+
+    pub fn aabb(&mut self, min: Point3<f32>, max: Point3<f32>, color: [f32; 4], depth_test: bool) {
+        let corner = |i: usize| {
+            Point3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        };
+        // Corners that differ in a single axis share an edge.
+        for i in 0..8 {
+            for &axis in &[1, 2, 4] {
+                if i & axis == 0 {
+                    self.line(corner(i), corner(i | axis), color, depth_test);
+                }
+            }
+        }
+    }
+
+    /// One circle around each axis.
+    pub fn sphere(&mut self, center: Point3<f32>, radius: f32, color: [f32; 4], depth_test: bool) {
+        let point = |axis: usize, angle: f32| {
+            let (sin, cos) = (angle.sin() * radius, angle.cos() * radius);
+            let offset = match axis {
+                0 => Vector3::new(0.0, cos, sin),
+                1 => Vector3::new(cos, 0.0, sin),
+                _ => Vector3::new(cos, sin, 0.0),
+            };
+            center + offset
+        };
+        for axis in 0..3 {
+            for i in 0..SPHERE_SEGMENTS {
+                let a = i as f32 / SPHERE_SEGMENTS as f32 * 2.0 * PI;
+                let b = (i + 1) as f32 / SPHERE_SEGMENTS as f32 * 2.0 * PI;
+                self.line(point(axis, a), point(axis, b), color, depth_test);
+            }
+        }
+    }
+
+    /// A square grid on the XZ plane, `size` units wide and split in `divisions`
+    /// cells along each side.
+    pub fn grid(&mut self, center: Point3<f32>, size: f32, divisions: u32, color: [f32; 4], depth_test: bool) {
+        let divisions = divisions.max(1);
+        let half = size / 2.0;
+        for i in 0..divisions + 1 {
+            let offset = -half + size * i as f32 / divisions as f32;
+            self.line(
+                center + Vector3::new(offset, 0.0, -half),
+                center + Vector3::new(offset, 0.0, half),
+                color,
+                depth_test,
+            );
+            self.line(
+                center + Vector3::new(-half, 0.0, offset),
+                center + Vector3::new(half, 0.0, offset),
+                color,
+                depth_test,
+            );
+        }
+    }
+
+    /// Three lines along the axes, crossing at `position`.
+    pub fn cross(&mut self, position: Point3<f32>, size: f32, color: [f32; 4], depth_test: bool) {
+        let half = size / 2.0;
+        for axis in 0..3 {
+            let mut offset = Vector3::zeros();
+            offset[axis] = half;
+            self.line(position - offset, position + offset, color, depth_test);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.depth_tested.is_empty() && self.on_top.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.depth_tested.clear();
+        self.on_top.clear();
+    }
+}
+
+/// Line list pipelines for the debug lines, with and without depth testing. Neither
+/// writes depth, so lines never hide each other.
+pub struct DebugDrawPipelines {
+    pub depth_tested: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    pub on_top: Arc<GraphicsPipelineAbstract + Send + Sync>,
+}
+
+impl DebugDrawPipelines {
+    pub fn new(
+        device: &Arc<Device>,
+        render_pass: &Arc<RenderPassAbstract + Send + Sync>,
+        reversed_z: bool,
+    ) -> Result<Self, VulkanBackendError> {
+        let vs = shaders::debug_vs::Shader::load(device.clone()).map_err(VulkanBackendError::ShaderLoading)?;
+        let fs = shaders::debug_fs::Shader::load(device.clone()).map_err(VulkanBackendError::ShaderLoading)?;
+
+        let pipeline = |depth_compare| -> Result<Arc<GraphicsPipelineAbstract + Send + Sync>, VulkanBackendError> {
+            Ok(Arc::new(
+                GraphicsPipeline::start()
+                    .vertex_input(SingleBufferDefinition::<DebugVertex>::new())
+                    .vertex_shader(
+                        vs.main_entry_point(),
+                        shaders::debug_vs::SpecializationConstants {
+                            reversed_z: reversed_z as i32,
+                        },
+                    )
+                    .line_list()
+                    .viewports_dynamic_scissors_irrelevant(1)
+                    .depth_stencil(DepthStencil {
+                        depth_compare,
+                        depth_write: false,
+                        ..DepthStencil::disabled()
+                    })
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+                    .blend_alpha_blending()
+                    .build(device.clone())?,
+            ))
+        };
+
+        Ok(Self {
+            depth_tested: pipeline(if reversed_z {
+                Compare::GreaterOrEqual
+            } else {
+                Compare::LessOrEqual
+            })?,
+            on_top: pipeline(Compare::Always)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [f32; 4] = [1.0; 4];
+
+    fn point(vertex: &DebugVertex) -> Point3<f32> {
+        Point3::new(vertex.position[0], vertex.position[1], vertex.position[2])
+    }
+
+    fn lines(vertexes: &[DebugVertex]) -> Vec<(Point3<f32>, Point3<f32>)> {
+        vertexes.chunks(2).map(|line| (point(&line[0]), point(&line[1]))).collect()
+    }
+
+    #[test]
+    fn aabb_draws_its_twelve_edges() {
+        let mut queue = DebugDrawQueue::default();
+        let (min, max) = (Point3::new(-1.0, 0.0, 2.0), Point3::new(1.0, 3.0, 4.0));
+        queue.aabb(min, max, WHITE, true);
+        let edges = lines(&queue.depth_tested);
+        assert_eq!(edges.len(), 12);
+
+        let mut per_axis = [0; 3];
+        for (i, &(from, to)) in edges.iter().enumerate() {
+            let changed: Vec<_> = (0..3).filter(|&axis| from[axis] != to[axis]).collect();
+            assert_eq!(changed.len(), 1);
+            assert_eq!((to - from)[changed[0]], (max - min)[changed[0]]);
+            per_axis[changed[0]] += 1;
+            assert!(edges[..i].iter().all(|&other| other != (from, to)));
+        }
+        assert_eq!(per_axis, [4, 4, 4]);
+    }
+
+    #[test]
+    fn sphere_draws_a_closed_ring_around_each_axis() {
+        let mut queue = DebugDrawQueue::default();
+        let center = Point3::new(1.0, 2.0, 3.0);
+        queue.sphere(center, 2.0, WHITE, true);
+        let segments = lines(&queue.depth_tested);
+        assert_eq!(segments.len(), 3 * SPHERE_SEGMENTS);
+
+        for (axis, ring) in segments.chunks(SPHERE_SEGMENTS).enumerate() {
+            for (i, &(from, to)) in ring.iter().enumerate() {
+                assert!((distance(&from, &center) - 2.0).abs() < 1e-5);
+                assert!((from[axis] - center[axis]).abs() < 1e-6);
+                assert!(distance(&to, &ring[(i + 1) % SPHERE_SEGMENTS].0) < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn grid_draws_both_sides_of_every_cell() {
+        let mut queue = DebugDrawQueue::default();
+        queue.grid(Point3::origin(), 4.0, 4, WHITE, true);
+        let grid = lines(&queue.depth_tested);
+        assert_eq!(grid.len(), 2 * 5);
+        assert!(grid.iter().all(|&(from, to)| from.y == 0.0 && to.y == 0.0));
+        assert!(grid.iter().all(|&(from, to)| distance(&from, &to) == 4.0));
+
+        // No divisions still draws the outline.
+        queue.clear();
+        queue.grid(Point3::origin(), 4.0, 0, WHITE, true);
+        assert_eq!(lines(&queue.depth_tested).len(), 4);
+    }
+
+    #[test]
+    fn cross_is_centered_on_its_position() {
+        let mut queue = DebugDrawQueue::default();
+        let position = Point3::new(1.0, -2.0, 0.5);
+        queue.cross(position, 2.0, WHITE, false);
+        let cross = lines(&queue.on_top);
+        assert_eq!(cross.len(), 3);
+        for (axis, &(from, to)) in cross.iter().enumerate() {
+            assert_eq!(center(&from, &to), position);
+            assert_eq!((to - from)[axis], 2.0);
+        }
+    }
+
+    #[test]
+    fn depth_test_picks_the_list_and_clear_empties_both() {
+        let mut queue = DebugDrawQueue::default();
+        assert!(queue.is_empty());
+        queue.line(Point3::origin(), Point3::new(1.0, 0.0, 0.0), WHITE, true);
+        queue.line(Point3::origin(), Point3::new(0.0, 1.0, 0.0), WHITE, false);
+        queue.line(Point3::origin(), Point3::new(0.0, 0.0, 1.0), WHITE, false);
+        assert_eq!(queue.depth_tested.len(), 2);
+        assert_eq!(queue.on_top.len(), 4);
+        assert!(!queue.is_empty());
+
+        queue.clear();
+        assert!(queue.is_empty());
+        assert!(queue.depth_tested.is_empty() && queue.on_top.is_empty());
+    }
+}
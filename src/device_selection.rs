@@ -0,0 +1,230 @@
+use error::VulkanBackendError;
+
+use std::env;
+use std::sync::Arc;
+
+use vulkano::instance::Instance;
+use vulkano::instance::PhysicalDevice;
+use vulkano::instance::PhysicalDeviceType;
+use vulkano::instance::QueueFamily;
+
+/// Environment variable that overrides the configured policy. A number picks the
+/// device by index, anything else is matched against the device names.
+pub const DEVICE_OVERRIDE_VAR: &str = "MURSTEN_VULKAN_DEVICE";
+
+/// How to choose a physical device when there's more than one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelection {
+    /// Highest scoring usable device. Discrete GPUs go first, software rasterizers last.
+    PreferDiscrete,
+    /// First usable device whose name contains the given text, ignoring case.
+    NameContains(String),
+    /// The device at the given position in the enumeration order.
+    Index(usize),
+}
+
+impl Default for DeviceSelection {
+    fn default() -> Self {
+        DeviceSelection::PreferDiscrete
+    }
+}
+
+impl DeviceSelection {
+    /// The policy set through `DEVICE_OVERRIDE_VAR`, if any.
+    pub fn from_env() -> Option<Self> {
+        parse_override(&env::var(DEVICE_OVERRIDE_VAR).ok()?)
+    }
+}
+
+fn parse_override(value: &str) -> Option<DeviceSelection> {
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+    Some(match value.parse() {
+        Ok(index) => DeviceSelection::Index(index),
+        Err(_) => DeviceSelection::NameContains(value.to_owned()),
+    })
+}
+
+/// What the selection looks at of a physical device.
+struct DeviceInfo {
+    index: usize,
+    name: String,
+    ty: PhysicalDeviceType,
+    // Queue families that can draw (and present, if needed).
+    usable_families: usize,
+}
+
+fn type_score(ty: PhysicalDeviceType) -> u32 {
+    match ty {
+        PhysicalDeviceType::DiscreteGpu => 1000,
+        PhysicalDeviceType::IntegratedGpu => 500,
+        PhysicalDeviceType::VirtualGpu => 250,
+        PhysicalDeviceType::Other => 100,
+        PhysicalDeviceType::Cpu => 10,
+    }
+}
+
+/// Scores a device, along with the reasons for the score to log.
+fn score(device: &DeviceInfo) -> (u32, Vec<String>) {
+    let mut reasons = Vec::new();
+    let mut score = type_score(device.ty);
+    reasons.push(format!("{:?} (+{})", device.ty, score));
+
+    // More usable families means more room to spread work later on.
+    let families_bonus = device.usable_families as u32 * 10;
+    score += families_bonus;
+    reasons.push(format!(
+        "{} usable queue families (+{})",
+        device.usable_families, families_bonus
+    ));
+
+    (score, reasons)
+}
+
+/// The position in `devices` of the one `selection` picks. Devices without a usable
+/// queue family are never picked.
+fn pick_device(devices: &[DeviceInfo], selection: &DeviceSelection) -> Option<usize> {
+    let mut usable = devices
+        .iter()
+        .enumerate()
+        .filter(|&(_, device)| device.usable_families > 0);
+    let chosen = match *selection {
+        DeviceSelection::PreferDiscrete => usable.max_by_key(|&(_, device)| score(device).0),
+        DeviceSelection::NameContains(ref name) => {
+            let name = name.to_lowercase();
+            usable.find(|&(_, device)| device.name.to_lowercase().contains(&name))
+        }
+        DeviceSelection::Index(index) => usable.find(|&(_, device)| device.index == index),
+    };
+    chosen.map(|(position, _)| position)
+}
+
+/// Picks a physical device and the queue family to use on it. The environment
+/// override, when present, wins over `selection`.
+pub fn select_device<'a, F>(
+    instance: &'a Arc<Instance>,
+    selection: &DeviceSelection,
+    usable_queue: F,
+) -> Result<(PhysicalDevice<'a>, QueueFamily<'a>), VulkanBackendError>
+where
+    F: Fn(QueueFamily) -> bool,
+{
+    let selection = match DeviceSelection::from_env() {
+        Some(overridden) => {
+            info!("Device selection overridden by {}: {:?}", DEVICE_OVERRIDE_VAR, overridden);
+            overridden
+        }
+        None => selection.clone(),
+    };
+
+    let devices: Vec<_> = PhysicalDevice::enumerate(instance).collect();
+    let infos: Vec<_> = devices
+        .iter()
+        .map(|device| DeviceInfo {
+            index: device.index(),
+            name: device.name(),
+            ty: device.ty(),
+            usable_families: device.queue_families().filter(|&qf| usable_queue(qf)).count(),
+        })
+        .collect();
+
+    for info in &infos {
+        if info.usable_families == 0 {
+            debug!("Device #{} {}: skipped, no usable queue family", info.index, info.name);
+            continue;
+        }
+        let (score, reasons) = score(info);
+        debug!(
+            "Device #{} {}: score {} ({})",
+            info.index,
+            info.name,
+            score,
+            reasons.join(", ")
+        );
+    }
+
+    if !infos.is_empty() && infos.iter().all(|info| info.usable_families == 0) {
+        return Err(VulkanBackendError::NoGraphicsQueueFamily);
+    }
+
+    let chosen = pick_device(&infos, &selection).ok_or(VulkanBackendError::NoSuitableDevice)?;
+    let device = devices[chosen];
+    let queue_family = device
+        .queue_families()
+        .filter(|&qf| usable_queue(qf))
+        .max_by_key(|qf| qf.queues_count())
+        .ok_or(VulkanBackendError::NoGraphicsQueueFamily)?;
+
+    info!(
+        "Using device #{} {} with queue family {} because of {:?}: {}",
+        device.index(),
+        device.name(),
+        queue_family.id(),
+        selection,
+        score(&infos[chosen]).1.join(", ")
+    );
+    Ok((device, queue_family))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(index: usize, name: &str, ty: PhysicalDeviceType, usable_families: usize) -> DeviceInfo {
+        DeviceInfo {
+            index,
+            name: name.to_owned(),
+            ty,
+            usable_families,
+        }
+    }
+
+    fn laptop() -> Vec<DeviceInfo> {
+        vec![
+            device(0, "Intel(R) UHD Graphics 620", PhysicalDeviceType::IntegratedGpu, 1),
+            device(1, "GeForce GTX 1050", PhysicalDeviceType::DiscreteGpu, 1),
+            device(2, "llvmpipe (LLVM 6.0, 256 bits)", PhysicalDeviceType::Cpu, 1),
+        ]
+    }
+
+    #[test]
+    fn discrete_gpus_are_preferred() {
+        assert_eq!(pick_device(&laptop(), &DeviceSelection::PreferDiscrete), Some(1));
+        let mut reversed = laptop();
+        reversed.reverse();
+        assert_eq!(pick_device(&reversed, &DeviceSelection::PreferDiscrete), Some(1));
+    }
+
+    #[test]
+    fn devices_without_usable_queues_are_skipped() {
+        let mut devices = laptop();
+        devices[1].usable_families = 0;
+        assert_eq!(pick_device(&devices, &DeviceSelection::PreferDiscrete), Some(0));
+        assert_eq!(pick_device(&devices, &DeviceSelection::Index(1)), None);
+        assert_eq!(pick_device(&[], &DeviceSelection::PreferDiscrete), None);
+    }
+
+    #[test]
+    fn override_by_name() {
+        let selection = parse_override("geforce").unwrap();
+        assert_eq!(selection, DeviceSelection::NameContains("geforce".to_owned()));
+        assert_eq!(pick_device(&laptop(), &selection), Some(1));
+        assert_eq!(pick_device(&laptop(), &DeviceSelection::NameContains("radeon".to_owned())), None);
+    }
+
+    #[test]
+    fn override_by_index() {
+        let selection = parse_override(" 2 ").unwrap();
+        assert_eq!(selection, DeviceSelection::Index(2));
+        assert_eq!(pick_device(&laptop(), &selection), Some(2));
+        assert_eq!(pick_device(&laptop(), &DeviceSelection::Index(3)), None);
+    }
+
+    #[test]
+    fn blank_override_is_ignored() {
+        assert_eq!(parse_override(""), None);
+        assert_eq!(parse_override("  "), None);
+    }
+}
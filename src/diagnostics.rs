@@ -0,0 +1,72 @@
+use log::Level;
+
+use std::sync::Arc;
+
+use vulkano::instance::debug::DebugCallback;
+use vulkano::instance::debug::Message;
+use vulkano::instance::debug::MessageTypes;
+use vulkano::instance::Instance;
+
+/// Log target used for everything reported by the validation layers, so it can be
+/// filtered separately with `RUST_LOG=vulkan=warn`.
+pub const VALIDATION_TARGET: &str = "vulkan";
+
+fn level(message: &Message) -> Level {
+    if message.ty.error {
+        Level::Error
+    } else if message.ty.warning || message.ty.performance_warning {
+        Level::Warn
+    } else if message.ty.information {
+        Level::Debug
+    } else {
+        Level::Trace
+    }
+}
+
+fn kind(message: &Message) -> &'static str {
+    if message.ty.error {
+        "error"
+    } else if message.ty.warning {
+        "warning"
+    } else if message.ty.performance_warning {
+        "performance"
+    } else if message.ty.information {
+        "information"
+    } else {
+        "debug"
+    }
+}
+
+/// Forwards validation layer reports to `log`. The instance must have been created
+/// with `ext_debug_report`. Messages are dropped once the returned callback is.
+///
+/// Vulkano doesn't hand the reported object type over to the callback, so the
+/// layer prefix is logged in its place.
+pub fn install_debug_callback(instance: &Arc<Instance>) -> Option<DebugCallback> {
+    let types = MessageTypes {
+        error: true,
+        warning: true,
+        performance_warning: true,
+        information: log_enabled!(target: VALIDATION_TARGET, Level::Debug),
+        debug: log_enabled!(target: VALIDATION_TARGET, Level::Trace),
+    };
+
+    let callback = DebugCallback::new(instance, types, |message| {
+        log!(
+            target: VALIDATION_TARGET,
+            level(message),
+            "[{}] {}: {}",
+            message.layer_prefix,
+            kind(message),
+            message.description
+        );
+    });
+
+    match callback {
+        Ok(callback) => Some(callback),
+        Err(err) => {
+            warn!("Couldn't install the validation debug callback: {:?}", err);
+            None
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::error;
+use std::fmt;
+
+use vulkano::buffer::cpu_access::ReadLockError;
+use vulkano::command_buffer::AutoCommandBufferBuilderContextError;
+use vulkano::command_buffer::BeginRenderPassError;
+use vulkano::command_buffer::BuildError;
+use vulkano::command_buffer::ClearColorImageError;
+use vulkano::command_buffer::CommandBufferExecError;
+use vulkano::command_buffer::CopyBufferImageError;
+use vulkano::command_buffer::DrawError;
+use vulkano::command_buffer::DrawIndexedError;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSetBuildError;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSetError;
+use vulkano::device::DeviceCreationError;
+use vulkano::framebuffer::FramebufferCreationError;
+use vulkano::framebuffer::RenderPassCreationError;
+use vulkano::image::ImageCreationError;
+use vulkano::instance::InstanceCreationError;
+use vulkano::instance::InstanceExtensions;
+use vulkano::instance::LayersListError;
+use vulkano::instance::SupportedExtensionsError;
+use vulkano::memory::DeviceMemoryAllocError;
+use vulkano::pipeline::GraphicsPipelineCreationError;
+use vulkano::sampler::SamplerCreationError;
+use vulkano::swapchain::AcquireError;
+use vulkano::swapchain::CapabilitiesError;
+use vulkano::swapchain::SwapchainCreationError;
+use vulkano::sync::FlushError;
+use vulkano::OomError;
+
+use vulkano_win::CreationError as WindowCreationError;
+
+/// Everything that can go wrong while setting up or driving the backend.
+#[derive(Debug)]
+pub enum VulkanBackendError {
+    /// Vulkan couldn't be loaded or queried at all.
+    Loading(SupportedExtensionsError),
+    /// Instance extensions needed to draw to a window that the driver doesn't provide.
+    MissingExtensions(InstanceExtensions),
+    LayersList(LayersListError),
+    /// The requested validation layer isn't installed.
+    MissingValidationLayer(String),
+    InstanceCreation(InstanceCreationError),
+    /// No physical device matched the selection policy.
+    NoSuitableDevice,
+    /// The chosen device has no queue family that can draw (and present, when windowed).
+    NoGraphicsQueueFamily,
+    DeviceCreation(DeviceCreationError),
+    WindowCreation(WindowCreationError),
+    /// The window went away before its size could be read.
+    WindowClosed,
+    /// The surface doesn't support any way of compositing its alpha.
+    NoCompositeAlpha,
+    SurfaceCapabilities(CapabilitiesError),
+    SwapchainCreation(SwapchainCreationError),
+    AcquireImage(AcquireError),
+    ShaderLoading(OomError),
+    RenderPassCreation(RenderPassCreationError),
+    PipelineCreation(GraphicsPipelineCreationError),
+    ImageCreation(ImageCreationError),
+    FramebufferCreation(FramebufferCreationError),
+    SamplerCreation(SamplerCreationError),
+    DeviceMemoryAlloc(DeviceMemoryAllocError),
+    /// An index of an uploaded mesh points past the end of its vertexes.
+    IndexOutOfRange { index: u32, vertexes: usize },
+    /// The amount of indices of an uploaded mesh isn't a multiple of three.
+    IncompleteTriangle { indices: usize },
+    /// An uploaded mesh has no triangles to draw.
+    EmptyMesh,
+    /// A descriptor set didn't match the layout of the pipeline it's meant for.
+    DescriptorSet(PersistentDescriptorSetError),
+    DescriptorSetBuild(PersistentDescriptorSetBuildError),
+    CommandBufferCreation(OomError),
+    BeginRenderPass(BeginRenderPassError),
+    EndRenderPass(AutoCommandBufferBuilderContextError),
+    Draw(DrawError),
+    DrawIndexed(DrawIndexedError),
+    CopyImage(CopyBufferImageError),
+    ClearImage(ClearColorImageError),
+    CommandBufferBuild(BuildError),
+    CommandBufferExecution(CommandBufferExecError),
+    /// The captured frame couldn't be read back from its buffer.
+    CaptureRead(ReadLockError),
+    Flush(FlushError),
+}
+
+impl fmt::Display for VulkanBackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::VulkanBackendError::*;
+        match *self {
+            Loading(ref err) => write!(f, "couldn't load Vulkan: {}", err),
+            MissingExtensions(ref missing) => write!(f, "missing instance extensions: {:?}", missing),
+            LayersList(ref err) => write!(f, "couldn't list the validation layers: {}", err),
+            MissingValidationLayer(ref layer) => write!(
+                f,
+                "the layer {} is not listed. Remember that validation layers are not available for Mac yet",
+                layer
+            ),
+            InstanceCreation(ref err) => write!(f, "failed to create Vulkan instance: {}", err),
+            NoSuitableDevice => write!(f, "no suitable device available"),
+            NoGraphicsQueueFamily => write!(f, "couldn't find a graphical queue family"),
+            DeviceCreation(ref err) => write!(f, "failed to create device: {}", err),
+            WindowCreation(ref err) => write!(f, "failed to create window: {:?}", err),
+            WindowClosed => write!(f, "the window no longer exists"),
+            NoCompositeAlpha => write!(f, "the surface supports no composite alpha mode"),
+            SurfaceCapabilities(ref err) => write!(f, "failed to get surface capabilities: {}", err),
+            SwapchainCreation(ref err) => write!(f, "failed to create swapchain: {}", err),
+            AcquireImage(ref err) => write!(f, "failed to acquire swapchain image: {}", err),
+            ShaderLoading(ref err) => write!(f, "failed to create shader module: {}", err),
+            RenderPassCreation(ref err) => write!(f, "failed to create render pass: {}", err),
+            PipelineCreation(ref err) => write!(f, "failed to create pipeline: {}", err),
+            ImageCreation(ref err) => write!(f, "failed to create image: {}", err),
+            FramebufferCreation(ref err) => write!(f, "failed to create framebuffer: {}", err),
+            SamplerCreation(ref err) => write!(f, "failed to create sampler: {}", err),
+            DeviceMemoryAlloc(ref err) => write!(f, "failed to allocate device memory: {}", err),
+            IndexOutOfRange { index, vertexes } => {
+                write!(f, "mesh index {} is out of range for {} vertexes", index, vertexes)
+            }
+            IncompleteTriangle { indices } => write!(f, "{} mesh indices don't make whole triangles", indices),
+            EmptyMesh => write!(f, "the mesh has no triangles"),
+            DescriptorSet(ref err) => write!(f, "failed to fill descriptor set: {}", err),
+            DescriptorSetBuild(ref err) => write!(f, "failed to build descriptor set: {}", err),
+            CommandBufferCreation(ref err) => write!(f, "failed to create command buffer: {}", err),
+            BeginRenderPass(ref err) => write!(f, "failed to begin render pass: {}", err),
+            EndRenderPass(ref err) => write!(f, "failed to end render pass: {}", err),
+            Draw(ref err) => write!(f, "failed to record draw: {}", err),
+            DrawIndexed(ref err) => write!(f, "failed to record indexed draw: {}", err),
+            CopyImage(ref err) => write!(f, "failed to record image copy: {}", err),
+            ClearImage(ref err) => write!(f, "failed to record image clear: {}", err),
+            CommandBufferBuild(ref err) => write!(f, "failed to build command buffer: {}", err),
+            CommandBufferExecution(ref err) => write!(f, "failed to execute command buffer: {}", err),
+            CaptureRead(ref err) => write!(f, "failed to read captured frame: {}", err),
+            Flush(ref err) => write!(f, "failed to submit frame: {}", err),
+        }
+    }
+}
+
+impl error::Error for VulkanBackendError {
+    fn description(&self) -> &str {
+        "error in the vulkan backend"
+    }
+}
+
+macro_rules! impl_from {
+    ($($error:ty => $variant:ident,)*) => {
+        $(
+            impl From<$error> for VulkanBackendError {
+                fn from(err: $error) -> Self {
+                    VulkanBackendError::$variant(err)
+                }
+            }
+        )*
+    };
+}
+
+impl_from! {
+    SupportedExtensionsError => Loading,
+    LayersListError => LayersList,
+    InstanceCreationError => InstanceCreation,
+    DeviceCreationError => DeviceCreation,
+    WindowCreationError => WindowCreation,
+    CapabilitiesError => SurfaceCapabilities,
+    SwapchainCreationError => SwapchainCreation,
+    AcquireError => AcquireImage,
+    RenderPassCreationError => RenderPassCreation,
+    GraphicsPipelineCreationError => PipelineCreation,
+    ImageCreationError => ImageCreation,
+    FramebufferCreationError => FramebufferCreation,
+    SamplerCreationError => SamplerCreation,
+    DeviceMemoryAllocError => DeviceMemoryAlloc,
+    PersistentDescriptorSetError => DescriptorSet,
+    PersistentDescriptorSetBuildError => DescriptorSetBuild,
+    BeginRenderPassError => BeginRenderPass,
+    AutoCommandBufferBuilderContextError => EndRenderPass,
+    DrawError => Draw,
+    DrawIndexedError => DrawIndexed,
+    CopyBufferImageError => CopyImage,
+    ClearColorImageError => ClearImage,
+    BuildError => CommandBufferBuild,
+    CommandBufferExecError => CommandBufferExecution,
+    ReadLockError => CaptureRead,
+    FlushError => Flush,
+}
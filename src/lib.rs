@@ -1,8 +1,10 @@
+extern crate image;
 extern crate mursten;
 extern crate nalgebra;
 #[macro_use]
 extern crate log;
 extern crate pretty_env_logger;
+extern crate rusttype;
 #[macro_use]
 extern crate vulkano;
 #[macro_use]
@@ -11,10 +13,43 @@ extern crate vulkano_win;
 extern crate winit;
 
 pub mod backend;
+pub mod device_selection;
+pub mod error;
 pub mod shaders;
+mod debug_draw;
+mod diagnostics;
+mod light;
+mod material;
+mod mesh;
+mod overlay;
+mod projection;
+mod render_state;
+mod renderer;
+mod screenshot;
+mod shadow;
+mod text;
+mod texture;
 
+pub use backend::InstanceData;
 pub use backend::Uniforms;
 pub use backend::VulkanBackend;
+pub use backend::VulkanBackendBuilder;
+pub use backend::WindowMode;
+pub use device_selection::DeviceSelection;
+pub use error::VulkanBackendError;
+pub use light::{Attenuation, Light, LightKind, MAX_LIGHTS};
+pub use material::{BlendMode, Material};
+pub use mesh::{MeshHandle, NormalMode, Winding};
+pub use projection::infinite_perspective;
+pub use render_state::{CullMode, DebugView, PolygonMode, RenderState};
+pub use shadow::ShadowFlags;
+pub use text::{FontHandle, TextAlign};
+pub use texture::{TextureFilter, TextureHandle, TextureOptions, TextureWrap};
+
+// Re-exported so games can configure the backend without depending on vulkano.
+pub use vulkano::format::Format;
+pub use vulkano::swapchain::PresentMode;
+pub use winit::VirtualKeyCode;
 
 // This crate should not refer to mursten_blocks directly, but it needs to know
 // the core traits to interact with the camera.
@@ -0,0 +1,287 @@
+use nalgebra::*;
+
+use shadow::{ShadowView, ShadowViews, MAX_SHADOW_VIEWS};
+
+/// How many lights the fragment shader looks at. Extra lights in a frame are ignored.
+pub const MAX_LIGHTS: usize = 16;
+
+/// Descriptor set the fragment shader reads the lights from.
+pub const LIGHT_SET: usize = 2;
+
+// Must match the constants in the fragment shader.
+const POINT_LIGHT: u32 = 0;
+const DIRECTIONAL_LIGHT: u32 = 1;
+const SPOT_LIGHT: u32 = 2;
+
+/// How the light of a point or spot light fades with distance:
+/// `1 / (constant + linear * d + quadratic * d²)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attenuation {
+    pub constant: f32,
+    pub linear: f32,
+    pub quadratic: f32,
+}
+
+impl Default for Attenuation {
+    fn default() -> Self {
+        Self {
+            constant: 1.0,
+            linear: 0.09,
+            quadratic: 0.032,
+        }
+    }
+}
+
+impl Attenuation {
+    /// Light that doesn't fade at all.
+    pub fn none() -> Self {
+        Self {
+            constant: 1.0,
+            linear: 0.0,
+            quadratic: 0.0,
+        }
+    }
+
+    /// Distance at which a light of the given intensity becomes too dim to notice.
+    /// Used as the far plane of point light shadows.
+    pub fn range(&self, intensity: f32) -> f32 {
+        const MAX_RANGE: f32 = 1000.0;
+        // Solves intensity / (c + l * d + q * d²) = 1 / 256.
+        let target = 256.0 * intensity.max(0.0) - self.constant;
+        if target <= 0.0 {
+            return 0.0;
+        }
+        let range = if self.quadratic > 0.0 {
+            let l = self.linear;
+            (-l + (l * l + 4.0 * self.quadratic * target).sqrt()) / (2.0 * self.quadratic)
+        } else if self.linear > 0.0 {
+            target / self.linear
+        } else {
+            MAX_RANGE
+        };
+        range.min(MAX_RANGE)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightKind {
+    /// Shines in every direction from `position`, like a torch.
+    Point {
+        position: Point3<f32>,
+        attenuation: Attenuation,
+    },
+    /// Comes from infinitely far away along `direction`, like the moon.
+    Directional { direction: Vector3<f32> },
+    /// A cone of light. Full strength inside `inner_angle`, fading to nothing at
+    /// `outer_angle`. Both angles are measured from `direction`, in radians.
+    Spot {
+        position: Point3<f32>,
+        direction: Vector3<f32>,
+        inner_angle: f32,
+        outer_angle: f32,
+        attenuation: Attenuation,
+    },
+}
+
+/// A light of the scene. Added each frame through `VulkanBackend::add_light`, or kept
+/// across frames with `VulkanBackend::set_persistent_light`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    /// Only the first directional light and the first point light of a frame that
+    /// have this set cast shadows. Spot lights never do.
+    pub casts_shadows: bool,
+}
+
+impl Light {
+    pub fn point(position: Point3<f32>, color: [f32; 3]) -> Self {
+        Self::new(LightKind::Point {
+            position,
+            attenuation: Attenuation::default(),
+        }, color)
+    }
+
+    pub fn directional(direction: Vector3<f32>, color: [f32; 3]) -> Self {
+        Self::new(LightKind::Directional { direction }, color)
+    }
+
+    pub fn spot(
+        position: Point3<f32>,
+        direction: Vector3<f32>,
+        inner_angle: f32,
+        outer_angle: f32,
+        color: [f32; 3],
+    ) -> Self {
+        Self::new(LightKind::Spot {
+            position,
+            direction,
+            inner_angle,
+            outer_angle,
+            attenuation: Attenuation::default(),
+        }, color)
+    }
+
+    fn new(kind: LightKind, color: [f32; 3]) -> Self {
+        Self {
+            kind,
+            color,
+            intensity: 1.0,
+            casts_shadows: false,
+        }
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn with_shadows(mut self, casts_shadows: bool) -> Self {
+        self.casts_shadows = casts_shadows;
+        self
+    }
+
+    /// Directional lights don't fade, so this does nothing for them.
+    pub fn with_attenuation(mut self, attenuation: Attenuation) -> Self {
+        match self.kind {
+            LightKind::Point { attenuation: ref mut a, .. } => *a = attenuation,
+            LightKind::Spot { attenuation: ref mut a, .. } => *a = attenuation,
+            LightKind::Directional { .. } => {}
+        }
+        self
+    }
+}
+
+/// A light as the fragment shader sees it, one element of the `lights` array. Its
+/// fields add up to a multiple of 16 bytes, the stride of arrays of structs in
+/// uniform blocks.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct GpuLight {
+    position: [f32; 4],
+    direction: [f32; 4],
+    // Premultiplied by the intensity.
+    color: [f32; 4],
+    attenuation: [f32; 4],
+    // Cosines of the inner and outer angles.
+    cone: [f32; 2],
+    kind: u32,
+    // Index of the first shadow view of the light, or -1.
+    shadow: i32,
+}
+
+impl GpuLight {
+    fn new(light: &Light, first_shadow_view: Option<usize>) -> Self {
+        let [r, g, b] = light.color;
+        let i = light.intensity;
+        let mut gpu_light = GpuLight {
+            color: [r * i, g * i, b * i, 1.0],
+            shadow: first_shadow_view.map_or(-1, |view| view as i32),
+            ..GpuLight::default()
+        };
+        match light.kind {
+            LightKind::Point { position, attenuation } => {
+                gpu_light.kind = POINT_LIGHT;
+                gpu_light.position = position.to_homogeneous().into();
+                gpu_light.attenuation = attenuation_array(attenuation);
+            }
+            LightKind::Directional { direction } => {
+                gpu_light.kind = DIRECTIONAL_LIGHT;
+                gpu_light.direction = direction.normalize().to_homogeneous().into();
+            }
+            LightKind::Spot {
+                position,
+                direction,
+                inner_angle,
+                outer_angle,
+                attenuation,
+            } => {
+                gpu_light.kind = SPOT_LIGHT;
+                gpu_light.position = position.to_homogeneous().into();
+                gpu_light.direction = direction.normalize().to_homogeneous().into();
+                gpu_light.attenuation = attenuation_array(attenuation);
+                gpu_light.cone = [inner_angle.cos(), outer_angle.cos()];
+            }
+        }
+        gpu_light
+    }
+}
+
+fn attenuation_array(attenuation: Attenuation) -> [f32; 4] {
+    [attenuation.constant, attenuation.linear, attenuation.quadratic, 0.0]
+}
+
+/// Every light of a frame and the views of its shadow map, bound once at `LIGHT_SET`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct LightUniforms {
+    lights: [GpuLight; MAX_LIGHTS],
+    shadow_views: [ShadowView; MAX_SHADOW_VIEWS],
+    count: u32,
+    _padding: [u32; 3],
+}
+
+impl LightUniforms {
+    pub fn new(lights: &[Light], shadows: &ShadowViews) -> Self {
+        let mut uniforms = LightUniforms {
+            lights: [GpuLight::default(); MAX_LIGHTS],
+            shadow_views: [ShadowView::default(); MAX_SHADOW_VIEWS],
+            count: 0,
+            _padding: [0; 3],
+        };
+        let lights = lights.iter().zip(shadows.first_views.iter());
+        for (gpu_light, (light, &first_view)) in uniforms.lights.iter_mut().zip(lights) {
+            *gpu_light = GpuLight::new(light, first_view);
+            uniforms.count += 1;
+        }
+        for (gpu_view, view) in uniforms.shadow_views.iter_mut().zip(shadows.views.iter()) {
+            *gpu_view = *view;
+        }
+        uniforms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brightness(attenuation: &Attenuation, intensity: f32, distance: f32) -> f32 {
+        intensity
+            / (attenuation.constant + attenuation.linear * distance + attenuation.quadratic * distance * distance)
+    }
+
+    #[test]
+    fn range_is_where_the_light_gets_too_dim() {
+        let attenuation = Attenuation::default();
+        let range = attenuation.range(1.0);
+        assert!((brightness(&attenuation, 1.0, range) - 1.0 / 256.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn range_grows_with_intensity() {
+        let attenuation = Attenuation::default();
+        assert!(attenuation.range(4.0) > attenuation.range(1.0));
+    }
+
+    #[test]
+    fn range_with_linear_attenuation_only() {
+        let attenuation = Attenuation {
+            constant: 1.0,
+            linear: 0.5,
+            quadratic: 0.0,
+        };
+        assert!((attenuation.range(1.0) - 510.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn range_is_capped_when_the_light_doesnt_fade() {
+        assert_eq!(Attenuation::none().range(1.0), 1000.0);
+    }
+
+    #[test]
+    fn range_of_a_dark_light_is_zero() {
+        assert_eq!(Attenuation::default().range(0.0), 0.0);
+        assert_eq!(Attenuation::default().range(-1.0), 0.0);
+    }
+}
@@ -0,0 +1,46 @@
+use texture::TextureHandle;
+
+/// How a surface is combined with what was already drawn behind it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    Opaque,
+    AlphaBlend,
+    Additive,
+}
+
+impl BlendMode {
+    /// Blended surfaces are drawn after every opaque one, furthest from the camera first.
+    pub fn is_blended(&self) -> bool {
+        *self != BlendMode::Opaque
+    }
+}
+
+/// Shading parameters of a surface, including how it blends with what's behind it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Material {
+    /// Multiplies the vertex color and the texture.
+    pub base_color: [f32; 4],
+    pub texture: Option<TextureHandle>,
+    /// Scales the specular strength of the lights.
+    pub specular_strength: f32,
+    pub shininess: f32,
+    /// Light given off by the surface itself, added after lighting.
+    pub emissive: [f32; 3],
+    /// Skips lighting altogether, the surface is shown with its plain color.
+    pub unlit: bool,
+    pub blend_mode: BlendMode,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            base_color: [1.0, 1.0, 1.0, 1.0],
+            texture: None,
+            specular_strength: 1.0,
+            shininess: 128.0,
+            emissive: [0.0, 0.0, 0.0],
+            unlit: false,
+            blend_mode: BlendMode::Opaque,
+        }
+    }
+}
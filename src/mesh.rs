@@ -0,0 +1,498 @@
+use backend::{InstanceData, Vertex};
+use error::VulkanBackendError;
+use material::Material;
+use render_state::RenderState;
+use shadow::ShadowFlags;
+
+use nalgebra::*;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::ImmutableBuffer;
+use vulkano::device::Queue;
+use vulkano::memory::DeviceMemoryAllocError;
+use vulkano::sync::GpuFuture;
+
+/// Refers to geometry that lives on the GPU. Obtained from `VulkanBackend::upload_mesh`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MeshHandle(u64);
+
+/// A retained mesh drawn with its own model matrix.
+#[derive(Debug, Clone, Copy)]
+pub struct MeshDraw {
+    pub mesh: MeshHandle,
+    pub model: Matrix4<f32>,
+    pub material: Material,
+    pub state: RenderState,
+    pub shadows: ShadowFlags,
+}
+
+/// A retained mesh drawn once per element of `MeshQueue::instances[start..start + count]`.
+#[derive(Debug, Clone, Copy)]
+pub struct InstancedDraw {
+    pub mesh: MeshHandle,
+    pub start: usize,
+    pub count: usize,
+    pub material: Material,
+    pub state: RenderState,
+    pub shadows: ShadowFlags,
+}
+
+/// Which way round the vertexes of a triangle go when it's seen from the front.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Winding {
+    Clockwise,
+    CounterClockwise,
+}
+
+impl Default for Winding {
+    /// What meshes have always been assumed to use.
+    fn default() -> Self {
+        Winding::Clockwise
+    }
+}
+
+/// Where the normals of converted meshes come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormalMode {
+    /// Each triangle uses its own face normal, so edges look sharp.
+    Flat,
+    /// Vertexes in the same position share the average of the normals of the
+    /// triangles around them, weighted by their area.
+    Smooth,
+    /// The normals the source mesh already has.
+    FromMesh,
+}
+
+impl Default for NormalMode {
+    fn default() -> Self {
+        NormalMode::Flat
+    }
+}
+
+/// Normal pointing out of the front of the triangle. Its length is twice the area of
+/// the triangle.
+pub fn face_normal(v1: &Vector3<f32>, v2: &Vector3<f32>, v3: &Vector3<f32>, winding: Winding) -> Vector3<f32> {
+    let normal = (v2 - v1).cross(&(v3 - v1));
+    match winding {
+        Winding::CounterClockwise => normal,
+        Winding::Clockwise => -normal,
+    }
+}
+
+/// Replaces the normal of each vertex with the normalized sum of the normals of
+/// every vertex in the same position.
+pub fn smooth_normals(vertexes: &mut [Vertex]) {
+    let mut sums: HashMap<[u32; 3], Vector3<f32>> = HashMap::new();
+    for vertex in vertexes.iter() {
+        *sums.entry(position_key(vertex)).or_insert_with(Vector3::zeros) += normal_of(vertex);
+    }
+    for vertex in vertexes.iter_mut() {
+        let sum = sums[&position_key(vertex)];
+        let normal = if sum.norm() > 0.0 { sum.normalize() } else { sum };
+        vertex.normal = normal.to_homogeneous().into();
+    }
+}
+
+/// Average position of the vertexes, or the origin when there are none.
+pub fn centroid(vertexes: &[Vertex]) -> Point3<f32> {
+    if vertexes.is_empty() {
+        return Point3::origin();
+    }
+    let sum = vertexes.iter().fold(Vector3::zeros(), |sum, vertex| {
+        let [x, y, z, _] = vertex.position;
+        sum + Vector3::new(x, y, z)
+    });
+    Point3::from_coordinates(sum / vertexes.len() as f32)
+}
+
+fn position_key(vertex: &Vertex) -> [u32; 3] {
+    let [x, y, z, _] = vertex.position;
+    [x.to_bits(), y.to_bits(), z.to_bits()]
+}
+
+fn normal_of(vertex: &Vertex) -> Vector3<f32> {
+    let [x, y, z, _] = vertex.normal;
+    Vector3::new(x, y, z)
+}
+
+/// Inverse transpose of the linear part of `model`, so normals stay perpendicular to
+/// their surfaces under non uniform scaling. Falls back to the identity when the
+/// matrix can't be inverted.
+pub fn normal_matrix(model: &Matrix4<f32>) -> Matrix4<f32> {
+    model
+        .fixed_slice::<U3, U3>(0, 0)
+        .into_owned()
+        .try_inverse()
+        .map(|inverse| inverse.transpose().to_homogeneous())
+        .unwrap_or_else(Matrix4::identity)
+}
+
+/// A run of immediate vertexes that share a model matrix, material, render state and
+/// shadow flags.
+#[derive(Debug, Clone, Copy)]
+pub struct VertexBatch {
+    pub start: usize,
+    pub count: usize,
+    pub model: Matrix4<f32>,
+    pub material: Material,
+    pub state: RenderState,
+    pub shadows: ShadowFlags,
+}
+
+/// Geometry that is uploaded again every frame. Each batch becomes one draw call.
+#[derive(Default)]
+pub struct ImmediateQueue {
+    pub vertexes: Vec<Vertex>,
+    pub batches: Vec<VertexBatch>,
+}
+
+impl ImmediateQueue {
+    pub fn push(
+        &mut self,
+        mut vertexes: Vec<Vertex>,
+        model: Matrix4<f32>,
+        material: Material,
+        state: RenderState,
+        shadows: ShadowFlags,
+    ) {
+        if vertexes.is_empty() {
+            return;
+        }
+        let start = self.vertexes.len();
+        let count = vertexes.len();
+        self.vertexes.append(&mut vertexes);
+
+        // Consecutive pushes with the same matrix, material, state and flags can share a
+        // draw call.
+        if let Some(last) = self.batches.last_mut() {
+            if last.model == model
+                && last.material == material
+                && last.state == state
+                && last.shadows == shadows
+                && last.start + last.count == start
+            {
+                last.count += count;
+                return;
+            }
+        }
+        self.batches.push(VertexBatch {
+            start,
+            count,
+            model,
+            material,
+            state,
+            shadows,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vertexes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.vertexes.clear();
+        self.batches.clear();
+    }
+}
+
+/// CPU side bookkeeping of the retained meshes. Uploads and releases are recorded
+/// here and carried out by the renderer at the start of the next frame, so handles
+/// can be created before `run` is called.
+#[derive(Default)]
+pub struct MeshQueue {
+    next_handle: u64,
+    pub uploads: Vec<(MeshHandle, Vec<Vertex>, Vec<u32>)>,
+    pub releases: Vec<MeshHandle>,
+    pub draws: Vec<MeshDraw>,
+    pub instanced_draws: Vec<InstancedDraw>,
+    pub instances: Vec<InstanceData>,
+}
+
+impl MeshQueue {
+    pub fn upload(&mut self, vertexes: Vec<Vertex>, indices: Vec<u32>) -> Result<MeshHandle, VulkanBackendError> {
+        if indices.is_empty() {
+            return Err(VulkanBackendError::EmptyMesh);
+        }
+        validate_indices(&indices, vertexes.len())?;
+        let handle = MeshHandle(self.next_handle);
+        self.next_handle += 1;
+        self.uploads.push((handle, vertexes, indices));
+        Ok(handle)
+    }
+
+    pub fn release(&mut self, handle: MeshHandle) {
+        self.releases.push(handle);
+    }
+
+    pub fn draw(
+        &mut self,
+        mesh: MeshHandle,
+        model: Matrix4<f32>,
+        material: Material,
+        state: RenderState,
+        shadows: ShadowFlags,
+    ) {
+        self.draws.push(MeshDraw {
+            mesh,
+            model,
+            material,
+            state,
+            shadows,
+        });
+    }
+
+    pub fn draw_instanced(
+        &mut self,
+        mesh: MeshHandle,
+        mut instances: Vec<InstanceData>,
+        material: Material,
+        state: RenderState,
+        shadows: ShadowFlags,
+    ) {
+        if instances.is_empty() {
+            return;
+        }
+        let start = self.instances.len();
+        let count = instances.len();
+        self.instances.append(&mut instances);
+        self.instanced_draws.push(InstancedDraw {
+            mesh,
+            start,
+            count,
+            material,
+            state,
+            shadows,
+        });
+    }
+
+    /// Forgets the draws queued this frame. Uploads and releases are kept.
+    pub fn clear_draws(&mut self) {
+        self.draws.clear();
+        self.instanced_draws.clear();
+        self.instances.clear();
+    }
+}
+
+/// Checks that the indices make whole triangles out of vertexes that exist, so the GPU
+/// never fetches past the end of the vertex buffer.
+pub fn validate_indices(indices: &[u32], vertex_count: usize) -> Result<(), VulkanBackendError> {
+    if indices.len() % 3 != 0 {
+        return Err(VulkanBackendError::IncompleteTriangle { indices: indices.len() });
+    }
+    match indices.iter().find(|&&index| index as usize >= vertex_count) {
+        Some(&index) => Err(VulkanBackendError::IndexOutOfRange {
+            index,
+            vertexes: vertex_count,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Merges vertexes that are equal in every attribute and returns the remaining ones
+/// along with the indices that rebuild the original list. Attributes are compared
+/// bit by bit, so `0.0` and `-0.0` count as different.
+pub fn weld(vertexes: Vec<Vertex>) -> (Vec<Vertex>, Vec<u32>) {
+    let mut unique = Vec::new();
+    let mut indices = Vec::with_capacity(vertexes.len());
+    let mut seen = HashMap::new();
+
+    for vertex in vertexes {
+        let index = *seen.entry(vertex_key(&vertex)).or_insert_with(|| {
+            unique.push(vertex);
+            (unique.len() - 1) as u32
+        });
+        indices.push(index);
+    }
+
+    (unique, indices)
+}
+
+fn vertex_key(vertex: &Vertex) -> [u32; 14] {
+    let mut key = [0; 14];
+    let attributes = vertex
+        .position
+        .iter()
+        .chain(vertex.normal.iter())
+        .chain(vertex.color.iter())
+        .chain(vertex.texture.iter());
+    for (k, value) in key.iter_mut().zip(attributes) {
+        *k = value.to_bits();
+    }
+    key
+}
+
+/// Device local geometry of a retained mesh.
+pub struct GpuMesh {
+    pub vertexes: Arc<ImmutableBuffer<[Vertex]>>,
+    pub indices: Arc<ImmutableBuffer<[u32]>>,
+    /// Average position of the vertexes in model space, used to sort blended draws.
+    pub center: Point3<f32>,
+}
+
+/// The device local buffers behind the mesh handles.
+#[derive(Default)]
+pub struct MeshStore {
+    meshes: HashMap<MeshHandle, GpuMesh>,
+}
+
+impl MeshStore {
+    /// Applies the queued uploads and releases. The returned future must be joined
+    /// before any of the new meshes are drawn.
+    pub fn sync(
+        &mut self,
+        queue: &Arc<Queue>,
+        pending: &mut MeshQueue,
+    ) -> Result<Option<Box<GpuFuture>>, DeviceMemoryAllocError> {
+        let mut upload_future: Option<Box<GpuFuture>> = None;
+
+        for (handle, vertexes, indices) in pending.uploads.drain(..) {
+            let center = centroid(&vertexes);
+            let (vertex_buffer, vertex_future) =
+                ImmutableBuffer::from_iter(vertexes.into_iter(), BufferUsage::vertex_buffer(), queue.clone())?;
+            let (index_buffer, index_future) =
+                ImmutableBuffer::from_iter(indices.into_iter(), BufferUsage::index_buffer(), queue.clone())?;
+            self.meshes.insert(
+                handle,
+                GpuMesh {
+                    vertexes: vertex_buffer,
+                    indices: index_buffer,
+                    center,
+                },
+            );
+            let future = vertex_future.join(index_future);
+            upload_future = Some(match upload_future {
+                Some(previous) => Box::new(previous.join(future)),
+                None => Box::new(future),
+            });
+        }
+
+        // Buffers still in use by a frame in flight are kept alive by its command buffer.
+        for handle in pending.releases.drain(..) {
+            self.meshes.remove(&handle);
+        }
+
+        Ok(upload_future)
+    }
+
+    pub fn get(&self, handle: MeshHandle) -> Option<&GpuMesh> {
+        self.meshes.get(&handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(x: f32, y: f32, z: f32) -> Vertex {
+        Vertex {
+            position: [x, y, z, 1.0],
+            normal: [0.0, 0.0, 1.0, 0.0],
+            color: [1.0, 1.0, 1.0, 1.0],
+            texture: [0.0, 0.0],
+        }
+    }
+
+    #[test]
+    fn face_normal_follows_the_winding() {
+        let (a, b, c) = (Vector3::zeros(), Vector3::x(), Vector3::y());
+        assert_eq!(face_normal(&a, &b, &c, Winding::CounterClockwise), Vector3::z());
+        assert_eq!(face_normal(&a, &b, &c, Winding::Clockwise), -Vector3::z());
+    }
+
+    #[test]
+    fn face_normal_length_is_twice_the_area() {
+        let (a, b, c) = (Vector3::zeros(), Vector3::x() * 2.0, Vector3::y() * 3.0);
+        let normal = face_normal(&a, &b, &c, Winding::CounterClockwise);
+        assert_eq!(normal.norm(), 6.0);
+    }
+
+    #[test]
+    fn smooth_normals_average_vertexes_in_the_same_position() {
+        let mut a = vertex(0.0, 0.0, 0.0);
+        a.normal = [1.0, 0.0, 0.0, 0.0];
+        let mut b = vertex(0.0, 0.0, 0.0);
+        b.normal = [0.0, 3.0, 0.0, 0.0];
+        let mut c = vertex(1.0, 0.0, 0.0);
+        c.normal = [0.0, 0.0, 2.0, 0.0];
+        let mut vertexes = vec![a, b, c];
+        smooth_normals(&mut vertexes);
+
+        // Weighted by length, like face normals weighted by area.
+        let expected = Vector3::new(1.0, 3.0, 0.0).normalize();
+        for vertex in &vertexes[..2] {
+            assert!((normal_of(vertex) - expected).norm() < 1e-6);
+        }
+        assert_eq!(vertexes[2].normal, [0.0, 0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn smooth_normals_leave_degenerate_normals_at_zero() {
+        let mut a = vertex(0.0, 0.0, 0.0);
+        a.normal = [0.0; 4];
+        let mut vertexes = vec![a];
+        smooth_normals(&mut vertexes);
+        assert_eq!(vertexes[0].normal, [0.0; 4]);
+    }
+
+    #[test]
+    fn weld_merges_identical_vertexes() {
+        let a = vertex(0.0, 0.0, 0.0);
+        let b = vertex(1.0, 0.0, 0.0);
+        let c = vertex(0.0, 1.0, 0.0);
+        let d = vertex(1.0, 1.0, 0.0);
+        let (unique, indices) = weld(vec![a, b, c, c, b, d]);
+        assert_eq!(unique.len(), 4);
+        assert_eq!(indices, vec![0, 1, 2, 2, 1, 3]);
+    }
+
+    #[test]
+    fn weld_keeps_vertexes_that_differ_in_any_attribute() {
+        let a = vertex(0.0, 0.0, 0.0);
+        let mut b = a;
+        b.texture = [1.0, 0.0];
+        let mut c = a;
+        c.position[0] = -0.0;
+        let (unique, indices) = weld(vec![a, b, c]);
+        assert_eq!(unique.len(), 3);
+        assert_eq!(indices, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn validate_indices_accepts_whole_triangles_in_range() {
+        assert!(validate_indices(&[0, 1, 2, 2, 1, 3], 4).is_ok());
+        assert!(validate_indices(&[], 0).is_ok());
+    }
+
+    #[test]
+    fn validate_indices_rejects_out_of_range() {
+        match validate_indices(&[0, 1, 4], 4) {
+            Err(VulkanBackendError::IndexOutOfRange { index: 4, vertexes: 4 }) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn validate_indices_rejects_incomplete_triangles() {
+        match validate_indices(&[0, 1, 2, 0], 3) {
+            Err(VulkanBackendError::IncompleteTriangle { indices: 4 }) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_meshes_are_rejected() {
+        let mut queue = MeshQueue::default();
+        match queue.upload(Vec::new(), Vec::new()) {
+            Err(VulkanBackendError::EmptyMesh) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        match queue.upload(vec![vertex(0.0, 0.0, 0.0); 3], Vec::new()) {
+            Err(VulkanBackendError::EmptyMesh) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(queue.uploads.is_empty());
+    }
+}
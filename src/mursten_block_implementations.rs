@@ -7,9 +7,17 @@ mod camera {
 
     impl SetCamera for VulkanBackend {
         fn set_camera(&mut self, transform: Matrix4<f32>, camera: &Camera) {
+            // `transform` takes world space to camera space, so the camera sits
+            // wherever its inverse takes the origin.
+            let camera_position = transform
+                .try_inverse()
+                .map(|inverse| inverse * Vector4::new(0.0, 0.0, 0.0, 1.0))
+                .unwrap_or_else(|| Vector4::new(0.0, 0.0, 0.0, 1.0));
+            let uniforms = self.get_uniforms();
             self.set_uniforms(Uniforms {
                 projection_view: camera.projection.clone() * transform,
-                ..Uniforms::default()
+                camera_position,
+                ..uniforms
             });
         }
     }
@@ -17,26 +25,50 @@ mod camera {
 
 mod render {
     use backend;
+    use error::VulkanBackendError;
+    use mesh::{self, MeshHandle, NormalMode, Winding};
     use nalgebra::*;
     use mursten_blocks::mesh_renderer::backend::RenderMesh;
     use mursten_blocks::geometry::{Mesh, Triangle, Vertex};
 
+    fn vertexes(mesh: Mesh, normals: NormalMode, winding: Winding) -> Vec<backend::Vertex> {
+        let mut vs = Vec::with_capacity(mesh.triangles.len() * 3);
+        for Triangle { v1, v2, v3 } in mesh.triangles {
+            let face = mesh::face_normal(&v1.position.coords, &v2.position.coords, &v3.position.coords, winding);
+            // Smooth normals are weighted by the area of each face, so they're
+            // normalized only after adding them up.
+            let face = match normals {
+                // Degenerate triangles have no area and no direction to normalize.
+                NormalMode::Flat if face.norm() > 0.0 => face.normalize(),
+                _ => face,
+            };
+            for v in vec![v1, v2, v3] {
+                let n = match normals {
+                    NormalMode::FromMesh => v.normal,
+                    NormalMode::Flat | NormalMode::Smooth => face,
+                };
+                vs.push((n, v).into());
+            }
+        }
+        if normals == NormalMode::Smooth {
+            mesh::smooth_normals(&mut vs);
+        }
+        vs
+    }
+
     impl RenderMesh for backend::VulkanBackend {
         fn queue_render(&mut self, m: Matrix4<f32>, mesh: Mesh) {
-            let vertexes = mesh.transform(&m).triangles.into_iter().fold(Vec::new(), |mut vs, t| {
-                let Triangle { v1, v2, v3 } = t;
-
-                let n1 = (v1.position - v3.position).cross(&(v1.position - v2.position)); // Le podes haber pifiado a la dirección de la normal
-                vs.push((n1, v1).into());
-
-                let n2 = (v2.position - v1.position).cross(&(v2.position - v3.position)); // Le podes haber pifiado a la dirección de la normal
-                vs.push((n2, v2).into());
+            let vertexes = vertexes(mesh, self.get_normal_mode(), self.get_winding());
+            self.enqueue_vertexes_with_model(vertexes, m);
+        }
+    }
 
-                let n3 = (v3.position - v2.position).cross(&(v3.position - v1.position)); // Le podes haber pifiado a la dirección de la normal
-                vs.push((n3, v3).into());
-                vs
-            });
-            self.enqueue_vertexes(vertexes);
+    impl backend::VulkanBackend {
+        /// Uploads the mesh to the GPU once, welding the vertexes the triangles share.
+        /// Draw it with `draw_mesh` to avoid converting and uploading it every frame
+        /// like `queue_render` does.
+        pub fn upload_mesh(&mut self, mesh: &Mesh) -> Result<MeshHandle, VulkanBackendError> {
+            self.upload_vertexes(vertexes(mesh.clone(), self.get_normal_mode(), self.get_winding()))
         }
     }
 
@@ -55,16 +87,21 @@ mod render {
 
 mod light {
     use backend;
+    use light::{self, Attenuation};
     use mursten_blocks::light::Light;
     use mursten_blocks::light::backend::SetLights;
     use nalgebra::*;
 
     impl SetLights for backend::VulkanBackend {
+        /// The light stays until the next call. Like it always has, it doesn't fade with
+        /// distance, its color is also the ambient color and `strength` scales the
+        /// ambient, diffuse and specular terms of every light.
         fn set_light(&mut self, light: Light) {
             let Light { point, color, strength } = light;
+            let light = light::Light::point(point, [color.x, color.y, color.z]).with_attenuation(Attenuation::none());
+            self.set_persistent_light(Some(light));
             let mut uniforms = self.get_uniforms();
-            uniforms.light_origin = Vector4::new(point.x, point.y, point.z, 1.0);
-            uniforms.light_color = Vector4::new(color.x, color.y, color.z, 1.0);
+            uniforms.ambient_light_color = Vector4::new(color.x, color.y, color.z, 1.0);
             uniforms.ambient_light_strength = strength;
             uniforms.diffuse_light_strength = strength;
             uniforms.specular_light_strength = strength;
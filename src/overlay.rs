@@ -0,0 +1,205 @@
+use error::VulkanBackendError;
+use texture::TextureHandle;
+
+use shaders;
+
+use std::sync::Arc;
+
+use vulkano::device::Device;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::pipeline::vertex::SingleBufferDefinition;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+
+/// A corner of an overlay quad. Positions are in pixels, from the top left corner
+/// of the screen.
+#[derive(Debug, Clone, Copy)]
+pub struct OverlayVertex {
+    pub position: [f32; 2],
+    pub texture: [f32; 2],
+    pub color: [f32; 4],
+}
+impl_vertex!(OverlayVertex, position, texture, color);
+
+/// Push constants of the overlay pipeline.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct OverlayConstants {
+    pub screen_size: [f32; 2],
+}
+
+/// A run of overlay vertexes that sample the same texture.
+#[derive(Debug, Clone, Copy)]
+pub struct OverlayBatch {
+    pub start: usize,
+    pub count: usize,
+    pub texture: Option<TextureHandle>,
+}
+
+/// Screen space quads queued during the render chain. They're drawn after the 3D
+/// scene in the order they were queued, so later quads cover earlier ones.
+#[derive(Default)]
+pub struct OverlayQueue {
+    pub vertexes: Vec<OverlayVertex>,
+    pub batches: Vec<OverlayBatch>,
+}
+
+impl OverlayQueue {
+    /// A quad covering `size` pixels from `position`, showing the part of `texture`
+    /// between `uv_min` and `uv_max` multiplied by `color`. Without a texture it's
+    /// plain `color`.
+    pub fn quad(
+        &mut self,
+        position: (f32, f32),
+        size: (f32, f32),
+        uv_min: (f32, f32),
+        uv_max: (f32, f32),
+        color: [f32; 4],
+        texture: Option<TextureHandle>,
+    ) {
+        let (x0, y0) = position;
+        let (x1, y1) = (x0 + size.0, y0 + size.1);
+        let (u0, v0) = uv_min;
+        let (u1, v1) = uv_max;
+        let corner = |x, y, u, v| OverlayVertex {
+            position: [x, y],
+            texture: [u, v],
+            color,
+        };
+        let corners = [
+            corner(x0, y0, u0, v0),
+            corner(x1, y0, u1, v0),
+            corner(x1, y1, u1, v1),
+            corner(x0, y1, u0, v1),
+        ];
+
+        let start = self.vertexes.len();
+        for &i in &[0, 1, 2, 0, 2, 3] {
+            self.vertexes.push(corners[i]);
+        }
+
+        // Consecutive quads with the same texture share a draw call.
+        if let Some(last) = self.batches.last_mut() {
+            if last.texture == texture && last.start + last.count == start {
+                last.count += 6;
+                return;
+            }
+        }
+        self.batches.push(OverlayBatch {
+            start,
+            count: 6,
+            texture,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vertexes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.vertexes.clear();
+        self.batches.clear();
+    }
+}
+
+/// Alpha blended and without depth testing, so the overlay always covers the scene.
+pub fn pipeline(
+    device: &Arc<Device>,
+    render_pass: &Arc<RenderPassAbstract + Send + Sync>,
+) -> Result<Arc<GraphicsPipelineAbstract + Send + Sync>, VulkanBackendError> {
+    let vs = shaders::overlay_vs::Shader::load(device.clone()).map_err(VulkanBackendError::ShaderLoading)?;
+    let fs = shaders::overlay_fs::Shader::load(device.clone()).map_err(VulkanBackendError::ShaderLoading)?;
+
+    Ok(Arc::new(
+        GraphicsPipeline::start()
+            .vertex_input(SingleBufferDefinition::<OverlayVertex>::new())
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .depth_stencil_disabled()
+            .fragment_shader(fs.main_entry_point(), ())
+            .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+            .blend_alpha_blending()
+            .build(device.clone())?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+    use texture::{TextureOptions, TextureQueue};
+
+    const WHITE: [f32; 4] = [1.0; 4];
+
+    fn textures() -> (TextureHandle, TextureHandle) {
+        let mut queue = TextureQueue::default();
+        let a = queue.upload(RgbaImage::new(1, 1), TextureOptions::default());
+        let b = queue.upload(RgbaImage::new(1, 1), TextureOptions::default());
+        (a, b)
+    }
+
+    fn quad(queue: &mut OverlayQueue, texture: Option<TextureHandle>) {
+        queue.quad((0.0, 0.0), (1.0, 1.0), (0.0, 0.0), (1.0, 1.0), WHITE, texture);
+    }
+
+    #[test]
+    fn quads_are_two_triangles_in_pixels() {
+        let mut queue = OverlayQueue::default();
+        queue.quad((10.0, 20.0), (30.0, 40.0), (0.25, 0.5), (0.75, 1.0), [1.0, 0.0, 0.0, 1.0], None);
+        let corners: Vec<_> = queue.vertexes.iter().map(|v| (v.position, v.texture)).collect();
+        assert_eq!(
+            corners,
+            vec![
+                ([10.0, 20.0], [0.25, 0.5]),
+                ([40.0, 20.0], [0.75, 0.5]),
+                ([40.0, 60.0], [0.75, 1.0]),
+                ([10.0, 20.0], [0.25, 0.5]),
+                ([40.0, 60.0], [0.75, 1.0]),
+                ([10.0, 60.0], [0.25, 1.0]),
+            ]
+        );
+        assert!(queue.vertexes.iter().all(|v| v.color == [1.0, 0.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn quads_with_the_same_texture_share_a_batch() {
+        let (a, b) = textures();
+        let mut queue = OverlayQueue::default();
+        quad(&mut queue, Some(a));
+        quad(&mut queue, Some(a));
+        quad(&mut queue, Some(b));
+        quad(&mut queue, None);
+        quad(&mut queue, None);
+        quad(&mut queue, Some(a));
+
+        let batches: Vec<_> = queue
+            .batches
+            .iter()
+            .map(|batch| (batch.start, batch.count, batch.texture))
+            .collect();
+        assert_eq!(
+            batches,
+            vec![(0, 12, Some(a)), (12, 6, Some(b)), (18, 12, None), (30, 6, Some(a))]
+        );
+        assert_eq!(queue.vertexes.len(), 36);
+    }
+
+    #[test]
+    fn clear_forgets_vertexes_and_batches() {
+        let mut queue = OverlayQueue::default();
+        assert!(queue.is_empty());
+        quad(&mut queue, None);
+        assert!(!queue.is_empty());
+
+        queue.clear();
+        assert!(queue.is_empty());
+        assert!(queue.batches.is_empty());
+
+        // Quads queued afterwards don't extend a forgotten batch.
+        quad(&mut queue, None);
+        assert_eq!(queue.batches.len(), 1);
+        assert_eq!((queue.batches[0].start, queue.batches[0].count), (0, 6));
+    }
+}
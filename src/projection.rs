@@ -0,0 +1,60 @@
+use nalgebra::*;
+
+/// A perspective projection like `Perspective3`, but without a far plane: depth
+/// approaches the far end of the range as distance goes to infinity.
+///
+/// With `reversed_z` the depth is 1 at the near plane and goes to 0, already in the
+/// range of a backend built with `VulkanBackendBuilder::reversed_z`, which keeps the
+/// precision of distant depths. Without it the depth goes from -1 to 1 like OpenGL's.
+pub fn infinite_perspective(aspect: f32, fovy: f32, znear: f32, reversed_z: bool) -> Matrix4<f32> {
+    let f = 1.0 / (fovy / 2.0).tan();
+    let (depth_scale, depth_offset) = if reversed_z { (0.0, znear) } else { (-1.0, -2.0 * znear) };
+    Matrix4::new(
+        f / aspect, 0.0, 0.0, 0.0,
+        0.0, f, 0.0, 0.0,
+        0.0, 0.0, depth_scale, depth_offset,
+        0.0, 0.0, -1.0, 0.0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn depth(projection: &Matrix4<f32>, distance: f32) -> f32 {
+        let clip = projection * Vector4::new(0.0, 0.0, -distance, 1.0);
+        clip.z / clip.w
+    }
+
+    #[test]
+    fn depth_goes_from_the_near_plane_to_infinity() {
+        let projection = infinite_perspective(1.0, 1.0, 0.1, false);
+        assert!((depth(&projection, 0.1) + 1.0).abs() < 1e-6);
+        assert!(depth(&projection, 1.0) < depth(&projection, 10.0));
+        assert!((depth(&projection, 1e6) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn reversed_depth_goes_from_one_to_zero() {
+        let projection = infinite_perspective(1.0, 1.0, 0.1, true);
+        assert!((depth(&projection, 0.1) - 1.0).abs() < 1e-6);
+        assert!(depth(&projection, 1.0) > depth(&projection, 10.0));
+        assert!(depth(&projection, 1e6) > 0.0);
+        assert!(depth(&projection, 1e6) < 1e-6);
+    }
+
+    #[test]
+    fn reversed_depth_keeps_distant_objects_apart() {
+        // Close to 0 floats are dense, so depths a metre apart far away stay distinct.
+        let projection = infinite_perspective(1.0, 1.0, 0.1, true);
+        assert!(depth(&projection, 10_000.0) > depth(&projection, 10_001.0));
+    }
+
+    #[test]
+    fn aspect_and_field_of_view_scale_x_and_y() {
+        let projection = infinite_perspective(2.0, ::std::f32::consts::FRAC_PI_2, 0.1, true);
+        let clip = projection * Vector4::new(1.0, 1.0, -1.0, 1.0);
+        assert!((clip.x / clip.w - 0.5).abs() < 1e-6);
+        assert!((clip.y / clip.w - 1.0).abs() < 1e-6);
+    }
+}
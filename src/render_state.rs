@@ -0,0 +1,108 @@
+use mesh::Winding;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CullMode {
+    /// Both sides are drawn, for things like foliage or paper.
+    None,
+    Front,
+    Back,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PolygonMode {
+    Fill,
+    /// Only the edges of the triangles. Needs the `fill_mode_non_solid` device
+    /// feature, without it triangles are filled.
+    Line,
+    /// Only the vertexes. Same requirements as `Line`.
+    Point,
+}
+
+/// How triangles are rasterized and tested against the depth buffer. Blending is
+/// part of the material.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RenderState {
+    pub cull_mode: CullMode,
+    /// Winding of the triangles that face the camera.
+    pub front_face: Winding,
+    pub polygon_mode: PolygonMode,
+    /// Whether fragments behind what's already drawn are discarded.
+    pub depth_test: bool,
+    /// Whether drawn fragments hide the ones drawn after them.
+    pub depth_write: bool,
+}
+
+impl Default for RenderState {
+    fn default() -> Self {
+        Self {
+            cull_mode: CullMode::None,
+            front_face: Winding::default(),
+            polygon_mode: PolygonMode::Fill,
+            depth_test: true,
+            depth_write: true,
+        }
+    }
+}
+
+/// What the main pass shows instead of the shaded scene, for debugging geometry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DebugView {
+    /// The scene as usual.
+    Shaded,
+    /// Shaded, but only the edges of the triangles are drawn. Falls back to `Shaded`
+    /// when the device can't draw lines.
+    Wireframe,
+    /// World space normals, with each axis mapped from -1..1 to 0..1 in a channel.
+    Normals,
+    /// Distance to the camera, black up close and white at `Uniforms::debug_depth_range`.
+    Depth,
+    /// Texture coordinates in the red and green channels.
+    Uvs,
+}
+
+impl Default for DebugView {
+    fn default() -> Self {
+        DebugView::Shaded
+    }
+}
+
+impl DebugView {
+    /// The view after this one, going back to `Shaded` after the last.
+    pub fn next(self) -> Self {
+        match self {
+            DebugView::Shaded => DebugView::Wireframe,
+            DebugView::Wireframe => DebugView::Normals,
+            DebugView::Normals => DebugView::Depth,
+            DebugView::Depth => DebugView::Uvs,
+            DebugView::Uvs => DebugView::Shaded,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_goes_through_every_view_and_back() {
+        let mut view = DebugView::Shaded;
+        let mut seen = Vec::new();
+        loop {
+            seen.push(view);
+            view = view.next();
+            if view == DebugView::Shaded {
+                break;
+            }
+        }
+        assert_eq!(
+            seen,
+            vec![
+                DebugView::Shaded,
+                DebugView::Wireframe,
+                DebugView::Normals,
+                DebugView::Depth,
+                DebugView::Uvs,
+            ]
+        );
+    }
+}
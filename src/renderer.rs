@@ -0,0 +1,1117 @@
+use backend::{InstanceData, Uniforms, Vertex};
+use debug_draw::{DebugConstants, DebugDrawPipelines, DebugDrawQueue, DebugVertex};
+use error::VulkanBackendError;
+use light::{Light, LightUniforms, LIGHT_SET};
+use material::{BlendMode, Material};
+use mesh::{centroid, normal_matrix, ImmediateQueue, InstancedDraw, MeshDraw, MeshQueue, MeshStore, Winding};
+use overlay::{self, OverlayConstants, OverlayQueue, OverlayVertex};
+use render_state::{CullMode, DebugView, PolygonMode, RenderState};
+use shadow::{ShadowConstants, ShadowFlags, ShadowMap, ShadowViews};
+use texture::{TextureQueue, TextureStore};
+
+use nalgebra::*;
+
+use shaders;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use vulkano::buffer::BufferAccess;
+use vulkano::buffer::BufferSlice;
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::buffer::ImmutableBuffer;
+use vulkano::command_buffer::AutoCommandBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::command_buffer::DynamicState;
+use vulkano::descriptor::descriptor_set::FixedSizeDescriptorSetsPool;
+use vulkano::descriptor::DescriptorSet;
+use vulkano::device::Device;
+use vulkano::device::Queue;
+use vulkano::format::ClearValue;
+use vulkano::format::Format;
+use vulkano::framebuffer::Framebuffer;
+use vulkano::framebuffer::FramebufferAbstract;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::image::attachment::AttachmentImage;
+use vulkano::image::ImageAccess;
+use vulkano::image::ImageUsage;
+use vulkano::image::ImageViewAccess;
+use vulkano::pipeline::blend::AttachmentBlend;
+use vulkano::pipeline::blend::BlendFactor;
+use vulkano::pipeline::blend::BlendOp;
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::pipeline::depth_stencil::DepthStencil;
+use vulkano::pipeline::vertex::OneVertexOneInstanceDefinition;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::sync::GpuFuture;
+
+/// Per draw data, bound at set 0 as the `Draw` block of the shaders. The emissive
+/// color is padded to four floats and the flags are `u32`, as uniform blocks have no
+/// `bool`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct DrawUniforms {
+    model: Matrix4<f32>,
+    normal: Matrix4<f32>,
+    base_color: [f32; 4],
+    emissive: [f32; 4],
+    specular_strength: f32,
+    shininess: f32,
+    unlit: u32,
+    receive_shadows: u32,
+}
+
+impl DrawUniforms {
+    fn new(model: Matrix4<f32>, material: &Material, shadows: ShadowFlags) -> Self {
+        let [r, g, b] = material.emissive;
+        Self {
+            model,
+            normal: normal_matrix(&model),
+            base_color: material.base_color,
+            emissive: [r, g, b, 0.0],
+            specular_strength: material.specular_strength,
+            shininess: material.shininess,
+            unlit: material.unlit as u32,
+            receive_shadows: shadows.receive as u32,
+        }
+    }
+}
+
+/// A single draw call, collected from the queues so they can be sorted.
+struct DrawCommand {
+    vertex_buffers: Vec<Arc<BufferAccess + Send + Sync>>,
+    index_buffer: Option<Arc<ImmutableBuffer<[u32]>>>,
+    model: Matrix4<f32>,
+    material: Material,
+    state: RenderState,
+    shadows: ShadowFlags,
+    /// World space position blended draws are sorted by.
+    center: Point3<f32>,
+}
+
+/// Everything that needs a pipeline of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct PipelineKey {
+    state: RenderState,
+    blend_mode: BlendMode,
+    debug_view: DebugView,
+}
+
+/// Value of the `debug_view` specialization constant of the fragment shader.
+fn debug_view_index(debug_view: DebugView) -> i32 {
+    match debug_view {
+        DebugView::Shaded | DebugView::Wireframe => 0,
+        DebugView::Normals => 1,
+        DebugView::Depth => 2,
+        DebugView::Uvs => 3,
+    }
+}
+
+/// Builds the pipelines of the main pass the first time each combination of state is
+/// drawn. They all share the same layout, so descriptor sets can be used with any of them.
+struct PipelineCache {
+    device: Arc<Device>,
+    render_pass: Arc<RenderPassAbstract + Send + Sync>,
+    vs: shaders::vs::Shader,
+    fs: shaders::fs::Shader,
+    reversed_z: bool,
+    pipelines: HashMap<PipelineKey, Arc<GraphicsPipelineAbstract + Send + Sync>>,
+}
+
+impl PipelineCache {
+    fn get(&mut self, mut key: PipelineKey) -> Result<Arc<GraphicsPipelineAbstract + Send + Sync>, VulkanBackendError> {
+        if key.debug_view == DebugView::Wireframe {
+            key.state.polygon_mode = PolygonMode::Line;
+        }
+        let requested_mode = key.state.polygon_mode;
+        if requested_mode != PolygonMode::Fill && !self.device.enabled_features().fill_mode_non_solid {
+            key.state.polygon_mode = PolygonMode::Fill;
+        }
+        if let Some(pipeline) = self.pipelines.get(&key) {
+            return Ok(pipeline.clone());
+        }
+
+        if key.state.polygon_mode != requested_mode {
+            debug!("{:?} isn't supported by the device, filling triangles instead", requested_mode);
+        }
+        debug!("Building pipeline for {:?}", key);
+        let builder = GraphicsPipeline::start()
+            .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
+            .vertex_shader(
+                self.vs.main_entry_point(),
+                shaders::vs::SpecializationConstants {
+                    reversed_z: self.reversed_z as i32,
+                },
+            )
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(
+                self.fs.main_entry_point(),
+                shaders::fs::SpecializationConstants {
+                    debug_view: debug_view_index(key.debug_view),
+                },
+            )
+            .render_pass(Subpass::from(self.render_pass.clone(), 0).unwrap());
+        let builder = match key.state.cull_mode {
+            CullMode::None => builder.cull_mode_disabled(),
+            CullMode::Front => builder.cull_mode_front(),
+            CullMode::Back => builder.cull_mode_back(),
+        };
+        let builder = match key.state.front_face {
+            Winding::Clockwise => builder.front_face_clockwise(),
+            Winding::CounterClockwise => builder.front_face_counter_clockwise(),
+        };
+        let builder = match key.state.polygon_mode {
+            PolygonMode::Fill => builder.polygon_mode_fill(),
+            PolygonMode::Line => builder.polygon_mode_line(),
+            PolygonMode::Point => builder.polygon_mode_point(),
+        };
+        let builder = builder.depth_stencil(DepthStencil {
+            depth_compare: match (key.state.depth_test, self.reversed_z) {
+                (false, _) => Compare::Always,
+                (true, false) => Compare::Less,
+                (true, true) => Compare::Greater,
+            },
+            depth_write: key.state.depth_write,
+            ..DepthStencil::disabled()
+        });
+        let builder = match key.blend_mode {
+            BlendMode::Opaque => builder.blend_pass_through(),
+            BlendMode::AlphaBlend => builder.blend_alpha_blending(),
+            BlendMode::Additive => builder.blend_collective(AttachmentBlend {
+                enabled: true,
+                color_op: BlendOp::Add,
+                color_source: BlendFactor::SrcAlpha,
+                color_destination: BlendFactor::One,
+                alpha_op: BlendOp::Add,
+                alpha_source: BlendFactor::One,
+                alpha_destination: BlendFactor::One,
+                mask_red: true,
+                mask_green: true,
+                mask_blue: true,
+                mask_alpha: true,
+            }),
+        };
+
+        let pipeline = Arc::new(builder.build(self.device.clone())?) as Arc<GraphicsPipelineAbstract + Send + Sync>;
+        self.pipelines.insert(key, pipeline.clone());
+        Ok(pipeline)
+    }
+}
+
+/// What the backend queued during the render chain.
+pub struct Frame<'a> {
+    pub clear_color: [f32; 4],
+    pub constants: Uniforms,
+    pub immediate: &'a mut ImmediateQueue,
+    pub meshes: &'a mut MeshQueue,
+    pub lights: &'a mut Vec<Light>,
+    pub shadow_distance: f32,
+    pub debug_view: DebugView,
+    pub debug_lines: &'a mut DebugDrawQueue,
+    pub overlay: &'a mut OverlayQueue,
+}
+
+/// A color attachment to copy into `buffer` once the frame has been drawn.
+pub struct Capture {
+    pub image: Arc<ImageAccess + Send + Sync>,
+    pub buffer: Arc<CpuAccessibleBuffer<[u8]>>,
+}
+
+/// Everything needed to turn the queued geometry into a command buffer. It doesn't
+/// care whether the color attachment is a swapchain image or an offscreen one.
+pub struct Renderer {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    render_pass: Arc<RenderPassAbstract + Send + Sync>,
+    pipelines: PipelineCache,
+    color_format: Format,
+    depth_format: Format,
+    samples: u32,
+    reversed_z: bool,
+    // Chunks go back to the pool once the frame using them has finished, so the
+    // memory is reused instead of allocated every frame.
+    vertex_pool: CpuBufferPool<Vertex>,
+    debug_vertex_pool: CpuBufferPool<DebugVertex>,
+    overlay_vertex_pool: CpuBufferPool<OverlayVertex>,
+    instance_pool: CpuBufferPool<InstanceData>,
+    // Bound as the instance buffer of everything that isn't instanced.
+    single_instance: Arc<ImmutableBuffer<[InstanceData]>>,
+    draw_uniform_pool: CpuBufferPool<DrawUniforms>,
+    draw_sets: FixedSizeDescriptorSetsPool<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    light_pool: CpuBufferPool<LightUniforms>,
+    light_sets: FixedSizeDescriptorSetsPool<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    shadow_map: ShadowMap,
+    // Whether the shadow map has been cleared at least once.
+    shadow_map_ready: bool,
+    debug_pipelines: DebugDrawPipelines,
+    overlay_pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    mesh_store: MeshStore,
+    texture_store: TextureStore,
+}
+
+impl Renderer {
+    pub fn new(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        color_format: Format,
+        depth_format: Option<Format>,
+        samples: u32,
+        reversed_z: bool,
+    ) -> Result<Self, VulkanBackendError> {
+        let vs = shaders::vs::Shader::load(device.clone()).map_err(VulkanBackendError::ShaderLoading)?;
+        let fs = shaders::fs::Shader::load(device.clone()).map_err(VulkanBackendError::ShaderLoading)?;
+
+        let depth_format = depth_format.unwrap_or_else(|| supported_depth_format(&device));
+        info!("Using {:?} for the depth buffer", depth_format);
+        let samples = supported_samples(&device, samples);
+        info!("Rendering with {} samples per pixel", samples);
+
+        // With multisampling everything is drawn into transient multisampled images,
+        // which are resolved into the color image at the end of the pass.
+        let render_pass = if samples > 1 {
+            Arc::new(
+                single_pass_renderpass!(device.clone(),
+                attachments: {
+                    multisampled_color: {
+                        load: Clear,
+                        store: DontCare,
+                        format: color_format,
+                        samples: samples,
+                    },
+                    color: {
+                        load: DontCare,
+                        store: Store,
+                        format: color_format,
+                        samples: 1,
+                    },
+                    depth: {
+                        load: Clear,
+                        store: DontCare,
+                        format: depth_format,
+                        samples: samples,
+                    }
+                },
+                pass: {
+                    color: [multisampled_color],
+                    depth_stencil: {depth},
+                    resolve: [color],
+                }
+            )?,
+            ) as Arc<RenderPassAbstract + Send + Sync>
+        } else {
+            Arc::new(
+                single_pass_renderpass!(device.clone(),
+                attachments: {
+                    color: {
+                        load: Clear,
+                        store: Store,
+                        format: color_format,
+                        samples: 1,
+                    },
+                     depth: {
+                        load: Clear,
+                        store: DontCare,
+                        format: depth_format,
+                        samples: 1,
+                    }
+                },
+                pass: {
+                    color: [color],
+                    depth_stencil: {depth}
+                }
+            )?,
+            ) as Arc<RenderPassAbstract + Send + Sync>
+        };
+
+        let mut pipelines = PipelineCache {
+            device: device.clone(),
+            render_pass: render_pass.clone(),
+            vs,
+            fs,
+            reversed_z,
+            pipelines: HashMap::new(),
+        };
+        // Built right away so errors show up here, and to lay out the descriptor sets.
+        let pipeline = pipelines.get(PipelineKey {
+            state: RenderState::default(),
+            blend_mode: Material::default().blend_mode,
+            debug_view: DebugView::default(),
+        })?;
+
+        let debug_pipelines = DebugDrawPipelines::new(&device, &render_pass, reversed_z)?;
+        let overlay_pipeline = overlay::pipeline(&device, &render_pass)?;
+
+        let vertex_pool = CpuBufferPool::vertex_buffer(device.clone());
+        let debug_vertex_pool = CpuBufferPool::vertex_buffer(device.clone());
+        let overlay_vertex_pool = CpuBufferPool::vertex_buffer(device.clone());
+        let instance_pool = CpuBufferPool::vertex_buffer(device.clone());
+        let (single_instance, single_instance_upload) = ImmutableBuffer::from_iter(
+            Some(InstanceData::default()).into_iter(),
+            BufferUsage::vertex_buffer(),
+            queue.clone(),
+        )?;
+        single_instance_upload.then_signal_fence_and_flush()?.wait(None)?;
+        let draw_uniform_pool = CpuBufferPool::uniform_buffer(device.clone());
+        let draw_sets = FixedSizeDescriptorSetsPool::new(pipeline.clone(), 0);
+        let light_pool = CpuBufferPool::uniform_buffer(device.clone());
+        let light_sets = FixedSizeDescriptorSetsPool::new(pipeline.clone(), LIGHT_SET);
+        let shadow_map = ShadowMap::new(&device)?;
+        let texture_store = TextureStore::new(&queue, pipeline.clone(), overlay_pipeline.clone())?;
+
+        Ok(Self {
+            device,
+            queue,
+            render_pass,
+            pipelines,
+            color_format,
+            depth_format,
+            samples,
+            reversed_z,
+            vertex_pool,
+            debug_vertex_pool,
+            overlay_vertex_pool,
+            instance_pool,
+            single_instance,
+            draw_uniform_pool,
+            draw_sets,
+            light_pool,
+            light_sets,
+            shadow_map,
+            shadow_map_ready: false,
+            debug_pipelines,
+            overlay_pipeline,
+            mesh_store: MeshStore::default(),
+            texture_store,
+        })
+    }
+
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &Arc<Queue> {
+        &self.queue
+    }
+
+    /// Wraps a color image in a framebuffer with a fresh depth buffer of the same size,
+    /// and a multisampled color buffer when multisampling.
+    pub fn framebuffer<I>(
+        &self,
+        image: I,
+        dimensions: [u32; 2],
+    ) -> Result<Arc<FramebufferAbstract + Send + Sync>, VulkanBackendError>
+    where
+        I: ImageViewAccess + Send + Sync + 'static,
+    {
+        let attachment_usage = ImageUsage {
+            transient_attachment: true,
+            input_attachment: false,
+            ..ImageUsage::none()
+        };
+
+        if self.samples > 1 {
+            let color_buffer = AttachmentImage::multisampled_with_usage(
+                self.device.clone(),
+                dimensions,
+                self.samples,
+                self.color_format,
+                attachment_usage,
+            )?;
+            let depth_buffer = AttachmentImage::multisampled_with_usage(
+                self.device.clone(),
+                dimensions,
+                self.samples,
+                self.depth_format,
+                attachment_usage,
+            )?;
+            return Ok(Arc::new(
+                Framebuffer::start(self.render_pass.clone())
+                    .add(color_buffer)?
+                    .add(image)?
+                    .add(depth_buffer)?
+                    .build()?,
+            ));
+        }
+
+        let depth_buffer = AttachmentImage::with_usage(
+            self.device.clone(),
+            dimensions,
+            self.depth_format,
+            attachment_usage,
+        )?;
+
+        Ok(Arc::new(
+            Framebuffer::start(self.render_pass.clone())
+                .add(image)?
+                .add(depth_buffer)?
+                .build()?,
+        ))
+    }
+
+    /// A host visible buffer big enough to hold an RGBA8 image of the given size.
+    pub fn capture_buffer(&self, dimensions: [u32; 2]) -> Result<Arc<CpuAccessibleBuffer<[u8]>>, VulkanBackendError> {
+        let size = (dimensions[0] * dimensions[1] * 4) as usize;
+        Ok(CpuAccessibleBuffer::from_iter(
+            self.device.clone(),
+            BufferUsage::all(),
+            (0..size).map(|_| 0u8),
+        )?)
+    }
+
+    /// Uploads and releases the meshes and textures queued since the last frame, after
+    /// `previous_frame_end`. The returned future takes its place, so the next frame is
+    /// submitted after it.
+    pub fn sync_resources(
+        &mut self,
+        meshes: &mut MeshQueue,
+        textures: &mut TextureQueue,
+        previous_frame_end: Box<GpuFuture>,
+    ) -> Result<Box<GpuFuture>, VulkanBackendError> {
+        let future = match self.mesh_store.sync(&self.queue, meshes)? {
+            Some(mesh_uploads) => Box::new(previous_frame_end.join(mesh_uploads)) as Box<GpuFuture>,
+            None => previous_frame_end,
+        };
+        self.texture_store.sync(&self.queue, textures, future)
+    }
+
+    /// The set 0 of a draw in the main pass, and in the shadow pass when it casts
+    /// shadows and the frame has one. Both read the same uniforms, but each is
+    /// allocated from the layout of the pipeline it's bound to.
+    #[cfg_attr(feature = "cargo-clippy", allow(type_complexity))]
+    fn draw_sets(
+        &mut self,
+        command: &DrawCommand,
+        shadow_pass: bool,
+    ) -> Result<(Arc<DescriptorSet + Send + Sync>, Option<Arc<DescriptorSet + Send + Sync>>), VulkanBackendError> {
+        let uniforms = self
+            .draw_uniform_pool
+            .next(DrawUniforms::new(command.model, &command.material, command.shadows))?;
+        let shadow_set = if shadow_pass && command.shadows.cast {
+            let set = self.shadow_map.draw_sets.next().add_buffer(uniforms.clone())?.build()?;
+            Some(Arc::new(set) as Arc<DescriptorSet + Send + Sync>)
+        } else {
+            None
+        };
+        let set = Arc::new(self.draw_sets.next().add_buffer(uniforms)?.build()?);
+        Ok((set, shadow_set))
+    }
+
+    fn light_set(
+        &mut self,
+        lights: &[Light],
+        shadows: &ShadowViews,
+    ) -> Result<Arc<DescriptorSet + Send + Sync>, VulkanBackendError> {
+        let uniforms = self.light_pool.next(LightUniforms::new(lights, shadows))?;
+        Ok(Arc::new(
+            self.light_sets
+                .next()
+                .add_buffer(uniforms)?
+                .add_sampled_image(self.shadow_map.image.clone(), self.shadow_map.sampler.clone())?
+                .build()?,
+        ))
+    }
+
+    /// Records the frame. The queues in `frame` are drained but keep their capacity
+    /// for the next one.
+    pub fn draw(
+        &mut self,
+        framebuffer: Arc<FramebufferAbstract + Send + Sync>,
+        dimensions: [u32; 2],
+        frame: Frame,
+        capture: Option<Capture>,
+    ) -> Result<AutoCommandBuffer, VulkanBackendError> {
+        let dynamic_state = DynamicState {
+            line_width: None,
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            scissors: None,
+        };
+
+        let mut commands = Vec::new();
+
+        if !frame.immediate.is_empty() {
+            let centers: Vec<_> = frame
+                .immediate
+                .batches
+                .iter()
+                .map(|batch| {
+                    let vertexes = &frame.immediate.vertexes[batch.start..batch.start + batch.count];
+                    batch.model.transform_point(&centroid(vertexes))
+                })
+                .collect();
+            // Everything goes in a single chunk, each batch draws its own slice of it.
+            let vertex_buffer = Arc::new(self.vertex_pool.chunk(frame.immediate.vertexes.drain(..))?);
+            for (batch, center) in frame.immediate.batches.drain(..).zip(centers) {
+                let slice = BufferSlice::from_typed_buffer_access(vertex_buffer.clone())
+                    .slice(batch.start..batch.start + batch.count)
+                    .unwrap();
+                commands.push(DrawCommand {
+                    vertex_buffers: vec![Arc::new(slice), self.single_instance.clone()],
+                    index_buffer: None,
+                    model: batch.model,
+                    material: batch.material,
+                    state: batch.state,
+                    shadows: batch.shadows,
+                    center,
+                });
+            }
+        }
+
+        for MeshDraw {
+            mesh,
+            model,
+            material,
+            state,
+            shadows,
+        } in frame.meshes.draws.drain(..)
+        {
+            let (vertex_buffer, index_buffer, center) = match self.mesh_store.get(mesh) {
+                Some(gpu_mesh) => (gpu_mesh.vertexes.clone(), gpu_mesh.indices.clone(), gpu_mesh.center),
+                None => {
+                    warn!("Tried to draw {:?}, which has no geometry on the GPU", mesh);
+                    continue;
+                }
+            };
+            commands.push(DrawCommand {
+                vertex_buffers: vec![vertex_buffer, self.single_instance.clone()],
+                index_buffer: Some(index_buffer),
+                model,
+                material,
+                state,
+                shadows,
+                center: model.transform_point(&center),
+            });
+        }
+
+        if !frame.meshes.instanced_draws.is_empty() {
+            // Instanced draws are sorted as a whole, by the average position of their instances.
+            let instance_centers: Vec<_> = frame
+                .meshes
+                .instanced_draws
+                .iter()
+                .map(|draw| {
+                    let center = self.mesh_store.get(draw.mesh).map_or_else(Point3::origin, |mesh| mesh.center);
+                    let instances = &frame.meshes.instances[draw.start..draw.start + draw.count];
+                    let sum = instances.iter().fold(Vector3::zeros(), |sum, instance| {
+                        sum + Matrix4::from(instance.instance_model).transform_point(&center).coords
+                    });
+                    Point3::from_coordinates(sum / draw.count as f32)
+                })
+                .collect();
+            let instance_buffer = Arc::new(self.instance_pool.chunk(frame.meshes.instances.drain(..))?);
+            for (
+                InstancedDraw {
+                    mesh,
+                    start,
+                    count,
+                    material,
+                    state,
+                    shadows,
+                },
+                center,
+            ) in frame.meshes.instanced_draws.drain(..).zip(instance_centers)
+            {
+                let (vertex_buffer, index_buffer) = match self.mesh_store.get(mesh) {
+                    Some(gpu_mesh) => (gpu_mesh.vertexes.clone(), gpu_mesh.indices.clone()),
+                    None => {
+                        warn!("Tried to draw {:?}, which has no geometry on the GPU", mesh);
+                        continue;
+                    }
+                };
+                let instances = BufferSlice::from_typed_buffer_access(instance_buffer.clone())
+                    .slice(start..start + count)
+                    .unwrap();
+                commands.push(DrawCommand {
+                    vertex_buffers: vec![vertex_buffer, Arc::new(instances)],
+                    index_buffer: Some(index_buffer),
+                    model: Matrix4::identity(),
+                    material,
+                    state,
+                    shadows,
+                    center,
+                });
+            }
+        }
+        frame.meshes.instances.clear();
+
+        // Blended surfaces need whatever is behind them to be drawn already, so they go
+        // after the opaque ones and from back to front. The sort is stable, so opaque
+        // things are drawn in the order they were queued.
+        let camera = Point3::from_homogeneous(frame.constants.camera_position).unwrap_or_else(Point3::origin);
+        commands.sort_by(|a, b| {
+            let a_blended = a.material.blend_mode.is_blended();
+            let b_blended = b.material.blend_mode.is_blended();
+            a_blended.cmp(&b_blended).then_with(|| {
+                if !a_blended {
+                    return Ordering::Equal;
+                }
+                let a_distance = distance_squared(&a.center, &camera);
+                let b_distance = distance_squared(&b.center, &camera);
+                b_distance.partial_cmp(&a_distance).unwrap_or(Ordering::Equal)
+            })
+        });
+
+        // With reversed depth the far plane is at 0. The resolved color attachment is
+        // overwritten entirely, so it isn't cleared.
+        let far_depth = if self.reversed_z { 0.0f32 } else { 1.0f32 };
+        let clear_values = if self.samples > 1 {
+            vec![frame.clear_color.into(), ClearValue::None, far_depth.into()]
+        } else {
+            vec![frame.clear_color.into(), far_depth.into()]
+        };
+
+        // Without casters the lights get no shadow views, so the shadow pass can be skipped.
+        let shadows = if commands.iter().any(|command| command.shadows.cast) {
+            ShadowViews::new(
+                frame.lights,
+                &frame.constants.projection_view,
+                self.reversed_z,
+                frame.shadow_distance,
+            )
+        } else {
+            ShadowViews::none(frame.lights.len())
+        };
+        let light_set = self.light_set(frame.lights, &shadows)?;
+        frame.lights.clear();
+
+        let shadow_pass = !shadows.views.is_empty();
+        let (draw_sets, shadow_sets): (Vec<_>, Vec<_>) = commands
+            .iter()
+            .map(|command| self.draw_sets(command, shadow_pass))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .unzip();
+
+        let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(self.device.clone(), self.queue.family())
+            .map_err(VulkanBackendError::CommandBufferCreation)?;
+
+        // The shadow map is bound to every frame's light set, so it's cleared once
+        // before its first use even when there's nothing to draw into it.
+        if shadow_pass || !self.shadow_map_ready {
+            builder = builder.begin_render_pass(self.shadow_map.framebuffer.clone(), false, vec![1.0f32.into()])?;
+            for view in &shadows.views {
+                let shadow_state = DynamicState {
+                    line_width: None,
+                    viewports: Some(vec![view.viewport()]),
+                    scissors: None,
+                };
+                let constants = ShadowConstants {
+                    view_projection: view.view_projection,
+                };
+                for (command, shadow_set) in commands.iter().zip(shadow_sets.iter()) {
+                    let shadow_set = match *shadow_set {
+                        Some(ref shadow_set) => shadow_set.clone(),
+                        None => continue,
+                    };
+                    builder = match command.index_buffer {
+                        Some(ref index_buffer) => builder.draw_indexed(
+                            self.shadow_map.pipeline.clone(),
+                            shadow_state.clone(),
+                            command.vertex_buffers.clone(),
+                            index_buffer.clone(),
+                            shadow_set,
+                            constants,
+                        )?,
+                        None => builder.draw(
+                            self.shadow_map.pipeline.clone(),
+                            shadow_state.clone(),
+                            command.vertex_buffers.clone(),
+                            shadow_set,
+                            constants,
+                        )?,
+                    };
+                }
+            }
+            builder = builder.end_render_pass()?;
+            self.shadow_map_ready = true;
+        }
+
+        let mut builder = builder.begin_render_pass(framebuffer, false, clear_values)?;
+
+        for (command, draw_set) in commands.into_iter().zip(draw_sets.into_iter()) {
+            let DrawCommand {
+                vertex_buffers,
+                index_buffer,
+                material,
+                state,
+                ..
+            } = command;
+            let pipeline = self.pipelines.get(PipelineKey {
+                state,
+                blend_mode: material.blend_mode,
+                debug_view: frame.debug_view,
+            })?;
+            let sets = (
+                draw_set,
+                self.texture_store.set(material.texture),
+                light_set.clone(),
+            );
+            builder = match index_buffer {
+                Some(index_buffer) => builder.draw_indexed(
+                    pipeline,
+                    dynamic_state.clone(),
+                    vertex_buffers,
+                    index_buffer,
+                    sets,
+                    frame.constants,
+                )?,
+                None => builder.draw(
+                    pipeline,
+                    dynamic_state.clone(),
+                    vertex_buffers,
+                    sets,
+                    frame.constants,
+                )?,
+            };
+        }
+
+        // Debug lines go over the scene. The depth tested ones first, so the ones on
+        // top are never hidden by them.
+        if !frame.debug_lines.is_empty() {
+            let constants = DebugConstants {
+                projection_view: frame.constants.projection_view,
+            };
+            let batches = vec![
+                (self.debug_pipelines.depth_tested.clone(), &mut frame.debug_lines.depth_tested),
+                (self.debug_pipelines.on_top.clone(), &mut frame.debug_lines.on_top),
+            ];
+            for (pipeline, vertexes) in batches {
+                if vertexes.is_empty() {
+                    continue;
+                }
+                let vertex_buffer = self.debug_vertex_pool.chunk(vertexes.drain(..))?;
+                builder = builder.draw(pipeline, dynamic_state.clone(), vec![Arc::new(vertex_buffer)], (), constants)?;
+            }
+        }
+
+        if !frame.overlay.is_empty() {
+            let constants = OverlayConstants {
+                screen_size: [dimensions[0] as f32, dimensions[1] as f32],
+            };
+            let vertex_buffer = Arc::new(self.overlay_vertex_pool.chunk(frame.overlay.vertexes.drain(..))?);
+            for batch in frame.overlay.batches.drain(..) {
+                let slice = BufferSlice::from_typed_buffer_access(vertex_buffer.clone())
+                    .slice(batch.start..batch.start + batch.count)
+                    .unwrap();
+                builder = builder.draw(
+                    self.overlay_pipeline.clone(),
+                    dynamic_state.clone(),
+                    vec![Arc::new(slice) as Arc<BufferAccess + Send + Sync>],
+                    self.texture_store.overlay_set(batch.texture),
+                    constants,
+                )?;
+            }
+        }
+
+        let builder = builder.end_render_pass()?;
+
+        let builder = match capture {
+            Some(Capture { image, buffer }) => builder.copy_image_to_buffer(image, buffer)?,
+            None => builder,
+        };
+
+        Ok(builder.build()?)
+    }
+}
+
+// Candidates for the depth buffer, best first. D16Unorm is supported everywhere.
+const DEPTH_FORMATS: [Format; 3] = [Format::D32Sfloat, Format::D24Unorm_S8Uint, Format::D16Unorm];
+
+/// The most precise depth format the device can use as a depth attachment.
+fn supported_depth_format(device: &Arc<Device>) -> Format {
+    DEPTH_FORMATS
+        .iter()
+        .cloned()
+        .find(|format| {
+            format
+                .properties(device.physical_device())
+                .optimal_tiling_features
+                .depth_stencil_attachment
+        })
+        .unwrap_or(Format::D16Unorm)
+}
+
+/// The highest sample count up to `requested` that both color and depth attachments
+/// support on the device.
+fn supported_samples(device: &Arc<Device>, requested: u32) -> u32 {
+    let limits = device.physical_device().limits();
+    let supported = limits.framebuffer_color_sample_counts() & limits.framebuffer_depth_sample_counts();
+    let samples = pick_samples(requested, supported);
+    if samples != requested {
+        warn!("{} samples per pixel requested, using {}", requested, samples);
+    }
+    samples
+}
+
+/// The highest power of two up to `requested` whose bit is set in `supported`, or 1.
+fn pick_samples(requested: u32, supported: u32) -> u32 {
+    let mut samples = requested.max(1).next_power_of_two();
+    if samples > requested.max(1) {
+        samples /= 2;
+    }
+    while samples > 1 && supported & samples == 0 {
+        samples /= 2;
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use texture::{TextureFilter, TextureOptions, TextureWrap};
+    use vulkano::device::DeviceExtensions;
+    use vulkano::image::{Dimensions, StorageImage};
+    use vulkano::instance::{Instance, InstanceExtensions, PhysicalDevice};
+    use vulkano::sync::now;
+
+    // A renderer on the first device that can draw, or None on machines without one.
+    // The tests that need a device pass without checking anything there.
+    fn headless_renderer() -> Option<Renderer> {
+        let instance = Instance::new(None, &InstanceExtensions::none(), None).ok()?;
+        let physical = PhysicalDevice::enumerate(&instance).next()?;
+        let family = physical.queue_families().find(|family| family.supports_graphics())?;
+        let (device, mut queues) = Device::new(
+            physical,
+            physical.supported_features(),
+            &DeviceExtensions::none(),
+            Some((family, 0.5)),
+        )
+        .ok()?;
+        let queue = queues.next()?;
+        Renderer::new(device, queue, Format::R8G8B8A8Srgb, None, 1, false).ok()
+    }
+
+    fn start(renderer: &Renderer) -> Box<GpuFuture> {
+        Box::new(now(renderer.device.clone()))
+    }
+
+    // Waits for `after` and copies the image back, row by row, four bytes per pixel.
+    fn read_back(renderer: &Renderer, image: Arc<StorageImage<Format>>, after: Box<GpuFuture>) -> Vec<u8> {
+        let dimensions = image.dimensions().width_height();
+        let buffer = CpuAccessibleBuffer::from_iter(
+            renderer.device.clone(),
+            BufferUsage::all(),
+            (0..dimensions[0] * dimensions[1] * 4).map(|_| 0u8),
+        )
+        .unwrap();
+        let family = renderer.queue.family();
+        let command_buffer = AutoCommandBufferBuilder::primary_one_time_submit(renderer.device.clone(), family)
+            .unwrap()
+            .copy_image_to_buffer(image, buffer.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+        after
+            .then_execute(renderer.queue.clone(), command_buffer)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+        buffer.read().map(|pixels| pixels.to_vec()).unwrap()
+    }
+
+    fn pixel(pixels: &[u8], width: usize, x: usize, y: usize) -> Vec<u8> {
+        pixels[(y * width + x) * 4..][..4].to_vec()
+    }
+
+    #[test]
+    fn dynamic_texture_updates_land_in_the_image() {
+        let mut renderer = match headless_renderer() {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let mut textures = TextureQueue::default();
+        let handle = textures.create_dynamic([4, 4], TextureOptions::default());
+        textures.update(handle, [1, 2], RgbaImage::from_pixel(2, 1, Rgba([255, 0, 0, 255])));
+
+        let previous = start(&renderer);
+        let future = renderer
+            .sync_resources(&mut MeshQueue::default(), &mut textures, previous)
+            .unwrap();
+        let image = renderer.texture_store.dynamic_image(handle).unwrap();
+        let pixels = read_back(&renderer, image, future);
+
+        assert_eq!(pixel(&pixels, 4, 1, 2), vec![255, 0, 0, 255]);
+        assert_eq!(pixel(&pixels, 4, 2, 2), vec![255, 0, 0, 255]);
+        // The rest keeps the transparent white it was cleared to.
+        assert_eq!(pixel(&pixels, 4, 0, 0), vec![255, 255, 255, 0]);
+        assert_eq!(pixel(&pixels, 4, 3, 2), vec![255, 255, 255, 0]);
+    }
+
+    #[test]
+    fn updates_are_applied_after_the_previous_frame() {
+        let mut renderer = match headless_renderer() {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let mut textures = TextureQueue::default();
+        let handle = textures.create_dynamic([2, 2], TextureOptions::default());
+        textures.update(handle, [0, 0], RgbaImage::from_pixel(2, 2, Rgba([255, 0, 0, 255])));
+        let previous = start(&renderer);
+        let first = renderer
+            .sync_resources(&mut MeshQueue::default(), &mut textures, previous)
+            .unwrap();
+
+        // Chained after the first sync without waiting on it, like the next frame.
+        textures.update(handle, [1, 1], RgbaImage::from_pixel(1, 1, Rgba([0, 0, 255, 255])));
+        let second = renderer
+            .sync_resources(&mut MeshQueue::default(), &mut textures, first)
+            .unwrap();
+        let image = renderer.texture_store.dynamic_image(handle).unwrap();
+        let pixels = read_back(&renderer, image, second);
+
+        assert_eq!(pixel(&pixels, 2, 0, 0), vec![255, 0, 0, 255]);
+        assert_eq!(pixel(&pixels, 2, 1, 1), vec![0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn textures_sample_white_until_synced() {
+        let renderer = match headless_renderer() {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let mut textures = TextureQueue::default();
+        let handle = textures.upload(RgbaImage::new(2, 2), TextureOptions::default());
+
+        let white = renderer.texture_store.set(None);
+        assert!(Arc::ptr_eq(&renderer.texture_store.set(Some(handle)), &white));
+        // Only the white texture's sampler exists before anything is synced.
+        assert_eq!(renderer.texture_store.sampler_count(), 1);
+    }
+
+    #[test]
+    fn uploaded_textures_are_bound_until_released() {
+        let mut renderer = match headless_renderer() {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let mut textures = TextureQueue::default();
+        let handle = textures.upload(RgbaImage::new(2, 2), TextureOptions::default());
+        let previous = start(&renderer);
+        renderer
+            .sync_resources(&mut MeshQueue::default(), &mut textures, previous)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        let white = renderer.texture_store.set(None);
+        let bound = renderer.texture_store.set(Some(handle));
+        assert!(!Arc::ptr_eq(&bound, &white));
+        assert!(Arc::ptr_eq(&renderer.texture_store.set(Some(handle)), &bound));
+
+        textures.release(handle);
+        let previous = start(&renderer);
+        renderer
+            .sync_resources(&mut MeshQueue::default(), &mut textures, previous)
+            .unwrap();
+        assert!(Arc::ptr_eq(&renderer.texture_store.set(Some(handle)), &white));
+    }
+
+    #[test]
+    fn textures_share_the_sampler_of_their_options() {
+        let mut renderer = match headless_renderer() {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let nearest = TextureOptions {
+            filter: TextureFilter::Nearest,
+            wrap: TextureWrap::ClampToEdge,
+        };
+        let mut textures = TextureQueue::default();
+        textures.upload(RgbaImage::new(1, 1), TextureOptions::default());
+        textures.upload(RgbaImage::new(1, 1), TextureOptions::default());
+        textures.upload(RgbaImage::new(1, 1), nearest);
+        textures.upload(RgbaImage::new(1, 1), nearest);
+        let previous = start(&renderer);
+        renderer
+            .sync_resources(&mut MeshQueue::default(), &mut textures, previous)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        // The white texture already created the default options' sampler.
+        assert_eq!(renderer.texture_store.sampler_count(), 2);
+    }
+
+    #[test]
+    fn overlay_pixels_start_at_the_top_left_corner() {
+        let mut renderer = match headless_renderer() {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let image = StorageImage::with_usage(
+            renderer.device.clone(),
+            Dimensions::Dim2d { width: 8, height: 8 },
+            Format::R8G8B8A8Srgb,
+            ImageUsage {
+                color_attachment: true,
+                transfer_source: true,
+                ..ImageUsage::none()
+            },
+            Some(renderer.queue.family()),
+        )
+        .unwrap();
+        let framebuffer = renderer.framebuffer(image.clone(), [8, 8]).unwrap();
+        let mut overlay = OverlayQueue::default();
+        overlay.quad((0.0, 0.0), (4.0, 2.0), (0.0, 0.0), (1.0, 1.0), [1.0, 0.0, 0.0, 1.0], None);
+        let frame = Frame {
+            clear_color: [0.0, 0.0, 0.0, 1.0],
+            constants: Uniforms::default(),
+            immediate: &mut ImmediateQueue::default(),
+            meshes: &mut MeshQueue::default(),
+            lights: &mut Vec::new(),
+            shadow_distance: 100.0,
+            debug_view: DebugView::default(),
+            debug_lines: &mut DebugDrawQueue::default(),
+            overlay: &mut overlay,
+        };
+        let command_buffer = renderer.draw(framebuffer, [8, 8], frame, None).unwrap();
+        let after = start(&renderer)
+            .then_execute(renderer.queue.clone(), command_buffer)
+            .unwrap();
+        let pixels = read_back(&renderer, image, Box::new(after));
+
+        assert_eq!(pixel(&pixels, 8, 0, 0), vec![255, 0, 0, 255]);
+        assert_eq!(pixel(&pixels, 8, 3, 1), vec![255, 0, 0, 255]);
+        assert_eq!(pixel(&pixels, 8, 4, 1), vec![0, 0, 0, 255]);
+        assert_eq!(pixel(&pixels, 8, 3, 2), vec![0, 0, 0, 255]);
+        assert_eq!(pixel(&pixels, 8, 0, 7), vec![0, 0, 0, 255]);
+    }
+
+
+    #[test]
+    fn supported_request_is_kept() {
+        assert_eq!(pick_samples(4, 0b1111), 4);
+        assert_eq!(pick_samples(1, 0b1), 1);
+    }
+
+    #[test]
+    fn request_rounds_down_to_a_power_of_two() {
+        assert_eq!(pick_samples(6, 0b1111), 4);
+        assert_eq!(pick_samples(0, 0b1111), 1);
+    }
+
+    #[test]
+    fn unsupported_counts_are_skipped() {
+        assert_eq!(pick_samples(8, 0b0011), 2);
+        assert_eq!(pick_samples(16, 0b0101), 4);
+        assert_eq!(pick_samples(4, 0b0001), 1);
+        assert_eq!(pick_samples(4, 0), 1);
+    }
+}
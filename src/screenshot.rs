@@ -0,0 +1,121 @@
+use image::png::PNGEncoder;
+use image::{ColorType, RgbaImage};
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::format::Format;
+
+/// What to do with the pixels of a frame once they are back in CPU memory.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureRequest {
+    pub keep_image: bool,
+    pub save_to: Vec<PathBuf>,
+}
+
+impl CaptureRequest {
+    pub fn is_empty(&self) -> bool {
+        !self.keep_image && self.save_to.is_empty()
+    }
+
+    /// Puts back a request whose frame was never presented, ahead of the ones made
+    /// since.
+    pub fn retry(&mut self, request: CaptureRequest) {
+        self.keep_image |= request.keep_image;
+        let newer = mem::replace(&mut self.save_to, request.save_to);
+        self.save_to.extend(newer);
+    }
+}
+
+/// A capture that has been recorded into a command buffer but not read back yet.
+pub struct PendingCapture {
+    pub request: CaptureRequest,
+    pub buffer: Arc<CpuAccessibleBuffer<[u8]>>,
+}
+
+/// Whether the pixels of an image with this format can be turned into an `RgbaImage`.
+pub fn is_supported(format: Format) -> bool {
+    match format {
+        Format::R8G8B8A8Unorm
+        | Format::R8G8B8A8Srgb
+        | Format::B8G8R8A8Unorm
+        | Format::B8G8R8A8Srgb => true,
+        _ => false,
+    }
+}
+
+/// Converts the raw contents of a color attachment into an RGBA image, swizzling BGRA
+/// formats as needed. Returns `None` for formats that `is_supported` rejects.
+pub fn to_rgba_image(format: Format, dimensions: [u32; 2], pixels: &[u8]) -> Option<RgbaImage> {
+    let mut pixels = pixels.to_vec();
+    match format {
+        Format::R8G8B8A8Unorm | Format::R8G8B8A8Srgb => (),
+        Format::B8G8R8A8Unorm | Format::B8G8R8A8Srgb => {
+            for pixel in pixels.chunks_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+        _ => return None,
+    }
+    RgbaImage::from_raw(dimensions[0], dimensions[1], pixels)
+}
+
+/// Writes the image as a PNG, whatever the extension of `path` says.
+pub fn save_png(image: &RgbaImage, path: &Path) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    PNGEncoder::new(file).encode(image, image.width(), image.height(), ColorType::RGBA(8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bgra_pixels_are_swizzled() {
+        let pixels = [1, 2, 3, 4, 5, 6, 7, 8];
+        let image = to_rgba_image(Format::B8G8R8A8Unorm, [2, 1], &pixels).unwrap();
+        assert_eq!(image.into_raw(), vec![3, 2, 1, 4, 7, 6, 5, 8]);
+    }
+
+    #[test]
+    fn rgba_pixels_are_kept() {
+        let pixels = [1, 2, 3, 4, 5, 6, 7, 8];
+        let image = to_rgba_image(Format::R8G8B8A8Srgb, [1, 2], &pixels).unwrap();
+        assert_eq!(image.dimensions(), (1, 2));
+        assert_eq!(image.into_raw(), pixels.to_vec());
+    }
+
+    #[test]
+    fn unsupported_formats_and_short_buffers_give_nothing() {
+        assert!(!is_supported(Format::R16G16B16A16Sfloat));
+        assert!(to_rgba_image(Format::R16G16B16A16Sfloat, [1, 1], &[0; 8]).is_none());
+        assert!(to_rgba_image(Format::R8G8B8A8Unorm, [2, 2], &[0; 4]).is_none());
+    }
+
+    #[test]
+    fn retried_requests_go_first() {
+        let mut request = CaptureRequest {
+            keep_image: false,
+            save_to: vec![PathBuf::from("b.png")],
+        };
+        request.retry(CaptureRequest {
+            keep_image: true,
+            save_to: vec![PathBuf::from("a.png")],
+        });
+        assert!(request.keep_image);
+        assert_eq!(request.save_to, vec![PathBuf::from("a.png"), PathBuf::from("b.png")]);
+    }
+
+    #[test]
+    fn screenshots_are_png_whatever_the_extension() {
+        let path = ::std::env::temp_dir().join("mursten_vulkan_backend_screenshot_test.jpg");
+        save_png(&RgbaImage::new(2, 2), &path).unwrap();
+        let bytes = ::std::fs::read(&path).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+        assert_eq!(&bytes[..8], b"\x89PNG\r\n\x1a\n");
+    }
+}
@@ -9,32 +9,64 @@ pub mod vs {
         const float PI_4 = 0.785398163397448309616;
 
         layout(location = 0) in vec4 position;
-        layout(location = 4) in vec4 normal;
-        layout(location = 8) in vec4 color;
-        layout(location = 12) in vec2 texture;
+        layout(location = 1) in vec4 normal;
+        layout(location = 2) in vec4 color;
+        layout(location = 3) in vec2 texture;
+
+        // Per instance attributes, see backend::InstanceData.
+        layout(location = 4) in mat4 instance_model;
+        layout(location = 8) in mat4 instance_normal;
+        layout(location = 12) in vec4 instance_tint;
+
         layout(location = 0) out vec4 outColor;
-        layout(location = 4) out vec4 outFragPos;
+        layout(location = 4) out vec4 outWorldPos;
         layout(location = 8) out vec4 outNormal;
+        layout(location = 12) out vec2 outTexture;
+
+        // Set when the depth buffer is reversed, see VulkanBackendBuilder::reversed_z.
+        layout(constant_id = 0) const int reversed_z = 0;
 
         layout(push_constant) uniform pushConstants {
             mat4 projection_view;
-            vec4 light_color;
-            vec4 light_origin;
+            vec4 ambient_light_color;
+            vec4 camera_position;
             float ambient_light_strength;
             float diffuse_light_strength;
             float specular_light_strength;
+            float debug_depth_range;
         } c;
 
+        layout(set = 0, binding = 0) uniform Draw {
+            mat4 model;
+            mat4 normal;
+            vec4 base_color;
+            vec4 emissive;
+            float specular_strength;
+            float shininess;
+            uint unlit;
+            uint receive_shadows;
+        } draw;
+
         void main() {
-            gl_Position = c.projection_view * position;
+            vec4 world_position = draw.model * instance_model * position;
+
+            gl_Position = c.projection_view * world_position;
             gl_Position.y = -gl_Position.y;
-            gl_Position.z = (gl_Position.z + gl_Position.w) / 2.0;
+            // From OpenGL's -1..1 to Vulkan's 0..1. Reversed projections already give
+            // 1 at the near plane and 0 at the far one, see infinite_perspective.
+            if (reversed_z == 0) {
+                gl_Position.z = (gl_Position.z + gl_Position.w) / 2.0;
+            }
 
-            outColor = color;
+            outColor = color * instance_tint;
+
+            // Lighting is done in world space, like the lights and the camera position.
+            outWorldPos = world_position;
 
-            outFragPos = c.projection_view * position;
+            // Only the linear part matters for directions, whatever w the vertex has.
+            outNormal = vec4(mat3(draw.normal) * mat3(instance_normal) * normal.xyz, 0.0);
 
-            outNormal = normal;
+            outTexture = texture;
         }
     "]
     struct Dummy;
@@ -47,42 +79,346 @@ pub mod fs {
         #version 450
 
         layout(location = 0) in vec4 inColor;
-        layout(location = 4) in vec4 inFragPos;
+        layout(location = 4) in vec4 inWorldPos;
         layout(location = 8) in vec4 inNormal;
+        layout(location = 12) in vec2 inTexture;
         layout(location = 0) out vec4 outColor;
 
         layout(push_constant) uniform pushConstants {
             mat4 projection_view;
-            vec4 light_color;
-            vec4 light_origin;
+            vec4 ambient_light_color;
+            vec4 camera_position;
             float ambient_light_strength;
             float diffuse_light_strength;
             float specular_light_strength;
+            float debug_depth_range;
         } c;
 
+        // Material of the draw, see renderer::DrawUniforms.
+        layout(set = 0, binding = 0) uniform Draw {
+            mat4 model;
+            mat4 normal;
+            vec4 base_color;
+            vec4 emissive;
+            float specular_strength;
+            float shininess;
+            uint unlit;
+            uint receive_shadows;
+        } draw;
+
+        layout(set = 1, binding = 0) uniform sampler2D tex;
+
+        // Each debug view is its own pipeline, see render_state::DebugView.
+        layout(constant_id = 0) const int debug_view = 0;
+        const int DEBUG_NORMALS = 1;
+        const int DEBUG_DEPTH = 2;
+        const int DEBUG_UVS = 3;
+
+        // See light::LightUniforms.
+        const uint MAX_LIGHTS = 16;
+        const uint POINT_LIGHT = 0;
+        const uint DIRECTIONAL_LIGHT = 1;
+        const uint SPOT_LIGHT = 2;
+
+        struct Light {
+            vec4 position;
+            vec4 direction;
+            vec4 color;
+            vec4 attenuation;
+            vec2 cone;
+            uint kind;
+            int shadow;
+        };
+
+        // See shadow::ShadowView.
+        const uint SHADOW_CASCADES = 3;
+        const uint MAX_SHADOW_VIEWS = 9;
+
+        struct ShadowView {
+            mat4 view_projection;
+            vec4 rect;
+        };
+
+        layout(set = 2, binding = 0) uniform Lights {
+            Light lights[MAX_LIGHTS];
+            ShadowView shadow_views[MAX_SHADOW_VIEWS];
+            uint count;
+        } lights;
+
+        layout(set = 2, binding = 1) uniform sampler2D shadow_map;
+
+        // Where the point lands in the atlas, and its depth as seen from the view.
+        bool project_shadow(uint view, vec3 worldPos, out vec3 coords) {
+            ShadowView v = lights.shadow_views[view];
+            vec4 p = v.view_projection * vec4(worldPos, 1.0);
+            vec3 ndc = p.xyz / p.w;
+            vec2 uv = vec2(ndc.x, -ndc.y) * 0.5 + 0.5;
+            float depth = (ndc.z + 1.0) / 2.0;
+            coords = vec3(v.rect.xy + uv * v.rect.zw, depth);
+            return all(greaterThanEqual(uv, vec2(0.0))) && all(lessThanEqual(uv, vec2(1.0))) && depth <= 1.0;
+        }
+
+        // Fraction of the 3x3 texels around the point that are lit. Samples are kept
+        // inside the tile so they don't pick up neighbouring views.
+        float pcf(uint view, vec3 coords, float bias) {
+            vec4 rect = lights.shadow_views[view].rect;
+            vec2 texel = 1.0 / vec2(textureSize(shadow_map, 0));
+            vec2 low = rect.xy + texel * 0.5;
+            vec2 high = rect.xy + rect.zw - texel * 0.5;
+            float lit = 0.0;
+            for (int x = -1; x <= 1; x++) {
+                for (int y = -1; y <= 1; y++) {
+                    vec2 uv = clamp(coords.xy + vec2(x, y) * texel, low, high);
+                    float closest = texture(shadow_map, uv).r;
+                    lit += coords.z - bias > closest ? 0.0 : 1.0;
+                }
+            }
+            return lit / 9.0;
+        }
+
+        float shadow(Light light, vec3 worldPos, vec3 lightDir, vec3 norm) {
+            if (light.shadow < 0) {
+                return 1.0;
+            }
+            float bias = max(0.005 * (1.0 - dot(norm, lightDir)), 0.0005);
+            vec3 coords;
+
+            if (light.kind == DIRECTIONAL_LIGHT) {
+                // Cascades go from nearest to farthest, the first one that covers the
+                // point has the most detail.
+                for (uint i = 0; i < SHADOW_CASCADES; i++) {
+                    uint view = uint(light.shadow) + i;
+                    if (project_shadow(view, worldPos, coords)) {
+                        return pcf(view, coords, bias);
+                    }
+                }
+                return 1.0;
+            }
+
+            // Point lights, one view per side of a cube: +X, -X, +Y, -Y, +Z, -Z.
+            vec3 v = worldPos - light.position.xyz;
+            vec3 a = abs(v);
+            uint face;
+            if (a.x >= a.y && a.x >= a.z) {
+                face = v.x > 0.0 ? 0u : 1u;
+            } else if (a.y >= a.z) {
+                face = v.y > 0.0 ? 2u : 3u;
+            } else {
+                face = v.z > 0.0 ? 4u : 5u;
+            }
+            uint view = uint(light.shadow) + face;
+            if (project_shadow(view, worldPos, coords)) {
+                return pcf(view, coords, bias);
+            }
+            return 1.0;
+        }
+
         float rand(vec2 co) {
             return fract(sin(dot(co.xy, vec2(12.9898,78.233))) * 43758.5453);
         }
 
         void main() {
-            vec4 ambient = c.ambient_light_strength * c.light_color;
-            ambient.w = 1.0;
-
-            vec4 norm = normalize(inNormal);
-            vec4 diffuse_origin = c.light_origin;
-            vec4 lightDir = normalize(diffuse_origin - inFragPos);  
-            float diff = max(dot(norm, lightDir), 0.0);
-            vec4 diffuse = c.diffuse_light_strength * diff * c.light_color;
-            diffuse.w = 1.0;
-
-            vec4 viewPos = vec4(0, 0, 0, 1);
-            vec4 viewDir = normalize(viewPos - inFragPos);
-            vec4 reflectDir = reflect(-lightDir, norm); 
-            float spec = pow(max(dot(viewDir, reflectDir), 0.0), 128);
-            vec4 specular = c.specular_light_strength * spec * c.light_color;  
-            specular.w = 1.0;
-
-            outColor = inColor * (ambient + diffuse + specular);
+            if (debug_view == DEBUG_NORMALS) {
+                outColor = vec4(normalize(inNormal.xyz) * 0.5 + 0.5, 1.0);
+                return;
+            }
+            if (debug_view == DEBUG_DEPTH) {
+                float depth = distance(c.camera_position.xyz, inWorldPos.xyz) / c.debug_depth_range;
+                outColor = vec4(vec3(clamp(depth, 0.0, 1.0)), 1.0);
+                return;
+            }
+            if (debug_view == DEBUG_UVS) {
+                outColor = vec4(fract(inTexture), 0.0, 1.0);
+                return;
+            }
+
+            vec4 albedo = inColor * draw.base_color * texture(tex, inTexture);
+            if (draw.unlit != 0) {
+                outColor = vec4(albedo.rgb + draw.emissive.rgb, albedo.a);
+                return;
+            }
+
+            vec3 ambient = c.ambient_light_strength * c.ambient_light_color.rgb;
+            vec3 diffuse = vec3(0.0);
+            vec3 specular = vec3(0.0);
+
+            vec3 norm = normalize(inNormal.xyz);
+            vec3 viewDir = normalize(c.camera_position.xyz - inWorldPos.xyz);
+
+            for (uint i = 0; i < lights.count; i++) {
+                Light light = lights.lights[i];
+
+                vec3 lightDir;
+                float attenuation = 1.0;
+                if (light.kind == DIRECTIONAL_LIGHT) {
+                    lightDir = -light.direction.xyz;
+                } else {
+                    vec3 toLight = light.position.xyz - inWorldPos.xyz;
+                    float distance = length(toLight);
+                    lightDir = toLight / distance;
+                    attenuation = 1.0 / (light.attenuation.x + light.attenuation.y * distance + light.attenuation.z * distance * distance);
+                    if (light.kind == SPOT_LIGHT) {
+                        float theta = dot(lightDir, -light.direction.xyz);
+                        float epsilon = max(light.cone.x - light.cone.y, 0.0001);
+                        attenuation *= clamp((theta - light.cone.y) / epsilon, 0.0, 1.0);
+                    }
+                }
+
+                if (draw.receive_shadows != 0) {
+                    attenuation *= shadow(light, inWorldPos.xyz, lightDir, norm);
+                }
+
+                float diff = max(dot(norm, lightDir), 0.0);
+                diffuse += c.diffuse_light_strength * diff * attenuation * light.color.rgb;
+
+                vec3 reflectDir = reflect(-lightDir, norm);
+                float spec = pow(max(dot(viewDir, reflectDir), 0.0), draw.shininess);
+                specular += c.specular_light_strength * draw.specular_strength * spec * attenuation * light.color.rgb;
+            }
+
+            vec3 color = (ambient + diffuse) * albedo.rgb + specular + draw.emissive.rgb;
+            outColor = vec4(color, albedo.a);
+        }
+    "]
+    struct Dummy;
+}
+
+pub mod shadow_vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+        #version 450
+
+        // Same names as in vs, the vertex definition matches them with the buffers.
+        layout(location = 0) in vec4 position;
+        layout(location = 4) in mat4 instance_model;
+
+        layout(push_constant) uniform pushConstants {
+            mat4 view_projection;
+        } c;
+
+        layout(set = 0, binding = 0) uniform Draw {
+            mat4 model;
+            mat4 normal;
+            vec4 base_color;
+            vec4 emissive;
+            float specular_strength;
+            float shininess;
+            uint unlit;
+            uint receive_shadows;
+        } draw;
+
+        void main() {
+            gl_Position = c.view_projection * draw.model * instance_model * position;
+            gl_Position.y = -gl_Position.y;
+            gl_Position.z = (gl_Position.z + gl_Position.w) / 2.0;
+        }
+    "]
+    struct Dummy;
+}
+
+pub mod shadow_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+        #version 450
+
+        // Only depth is written.
+        void main() {
+        }
+    "]
+    struct Dummy;
+}
+
+pub mod debug_vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+        #version 450
+
+        layout(location = 0) in vec4 position;
+        layout(location = 1) in vec4 color;
+
+        layout(location = 0) out vec4 outColor;
+
+        layout(constant_id = 0) const int reversed_z = 0;
+
+        layout(push_constant) uniform pushConstants {
+            mat4 projection_view;
+        } c;
+
+        void main() {
+            gl_Position = c.projection_view * position;
+            gl_Position.y = -gl_Position.y;
+            if (reversed_z == 0) {
+                gl_Position.z = (gl_Position.z + gl_Position.w) / 2.0;
+            }
+
+            outColor = color;
+        }
+    "]
+    struct Dummy;
+}
+
+pub mod debug_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+        #version 450
+
+        layout(location = 0) in vec4 inColor;
+        layout(location = 0) out vec4 outColor;
+
+        void main() {
+            outColor = inColor;
+        }
+    "]
+    struct Dummy;
+}
+
+pub mod overlay_vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+        #version 450
+
+        layout(location = 0) in vec2 position;
+        layout(location = 1) in vec2 texture;
+        layout(location = 2) in vec4 color;
+
+        layout(location = 0) out vec2 outTexture;
+        layout(location = 1) out vec4 outColor;
+
+        layout(push_constant) uniform pushConstants {
+            vec2 screen_size;
+        } c;
+
+        void main() {
+            // Pixels grow down from the top left corner, like Vulkan's y axis.
+            gl_Position = vec4(position / c.screen_size * 2.0 - 1.0, 0.0, 1.0);
+
+            outTexture = texture;
+            outColor = color;
+        }
+    "]
+    struct Dummy;
+}
+
+pub mod overlay_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+        #version 450
+
+        layout(location = 0) in vec2 inTexture;
+        layout(location = 1) in vec4 inColor;
+        layout(location = 0) out vec4 outColor;
+
+        // See texture::OVERLAY_TEXTURE_SET.
+        layout(set = 0, binding = 0) uniform sampler2D tex;
+
+        void main() {
+            outColor = inColor * texture(tex, inTexture);
         }
     "]
     struct Dummy;
@@ -0,0 +1,484 @@
+use backend::{InstanceData, Vertex};
+use error::VulkanBackendError;
+use light::{Light, LightKind};
+
+use nalgebra::*;
+
+use shaders;
+
+use std::f32::consts::FRAC_PI_2;
+use std::sync::Arc;
+
+use vulkano::descriptor::descriptor_set::FixedSizeDescriptorSetsPool;
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::framebuffer::Framebuffer;
+use vulkano::framebuffer::FramebufferAbstract;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::image::attachment::AttachmentImage;
+use vulkano::pipeline::vertex::OneVertexOneInstanceDefinition;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::sampler::Filter;
+use vulkano::sampler::MipmapMode;
+use vulkano::sampler::Sampler;
+use vulkano::sampler::SamplerAddressMode;
+
+/// Every device can render to and sample from this one.
+const SHADOW_FORMAT: Format = Format::D16Unorm;
+
+/// Size in pixels of each view in the shadow atlas.
+const SHADOW_TILE_SIZE: u32 = 1024;
+
+/// Slices the view frustum is split in for directional lights. Must match the
+/// fragment shader.
+pub const SHADOW_CASCADES: usize = 3;
+
+// Point lights aren't given a cube map: each side of their cube is a plain tile of the
+// atlas, so every light is sampled through the same 2D sampler.
+const CUBE_FACES: usize = 6;
+
+/// One directional light and one point light cast shadows at a time.
+pub const MAX_SHADOW_VIEWS: usize = SHADOW_CASCADES + CUBE_FACES;
+
+// Views are laid out in a square grid of tiles.
+const ATLAS_COLUMNS: u32 = 3;
+
+// Blend between logarithmic (1.0) and uniform (0.0) cascade splits.
+const CASCADE_SPLIT_LAMBDA: f32 = 0.75;
+
+// Near plane of the cube faces of point lights.
+const POINT_SHADOW_NEAR: f32 = 0.05;
+
+/// Whether a draw takes part in shadowing. Kept apart from the material so the same
+/// material can be used by casters and non casters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShadowFlags {
+    /// Whether the draw is drawn into the shadow map.
+    pub cast: bool,
+    /// Whether shadows from other draws darken it.
+    pub receive: bool,
+}
+
+impl Default for ShadowFlags {
+    fn default() -> Self {
+        Self {
+            cast: true,
+            receive: true,
+        }
+    }
+}
+
+/// A view from a light, drawn into its own tile of the atlas. The fragment shader
+/// reads it from the `shadow_views` array next to the lights.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ShadowView {
+    pub view_projection: Matrix4<f32>,
+    // Offset and size of the tile, in texture coordinates.
+    pub rect: [f32; 4],
+}
+
+impl Default for ShadowView {
+    fn default() -> Self {
+        Self {
+            view_projection: Matrix4::identity(),
+            rect: [0.0; 4],
+        }
+    }
+}
+
+impl ShadowView {
+    fn new(index: usize, view_projection: Matrix4<f32>) -> Self {
+        let size = 1.0 / ATLAS_COLUMNS as f32;
+        let (column, row) = (index as u32 % ATLAS_COLUMNS, index as u32 / ATLAS_COLUMNS);
+        Self {
+            view_projection,
+            rect: [column as f32 * size, row as f32 * size, size, size],
+        }
+    }
+
+    pub fn viewport(&self) -> Viewport {
+        let atlas_size = (SHADOW_TILE_SIZE * ATLAS_COLUMNS) as f32;
+        Viewport {
+            origin: [self.rect[0] * atlas_size, self.rect[1] * atlas_size],
+            dimensions: [SHADOW_TILE_SIZE as f32, SHADOW_TILE_SIZE as f32],
+            depth_range: 0.0..1.0,
+        }
+    }
+}
+
+/// Push constants of the shadow pass.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ShadowConstants {
+    pub view_projection: Matrix4<f32>,
+}
+
+/// The views to render this frame, and for each light the index of its first view.
+pub struct ShadowViews {
+    pub views: Vec<ShadowView>,
+    pub first_views: Vec<Option<usize>>,
+}
+
+impl ShadowViews {
+    /// Picks the first directional light and the first point light that cast shadows.
+    /// Spot lights and any other light don't cast shadows. Directional lights cast them
+    /// up to the far plane of `projection_view`, or `shadow_distance` past its near plane
+    /// when the far plane is at infinity. Point lights get six
+    /// views, +X, -X, +Y, -Y, +Z and -Z, drawn into 2D tiles rather than a cube map.
+    pub fn new(lights: &[Light], projection_view: &Matrix4<f32>, reversed_z: bool, shadow_distance: f32) -> Self {
+        let mut views = Vec::new();
+        let mut first_views = Vec::new();
+        let mut directional_done = false;
+        let mut point_done = false;
+
+        for light in lights {
+            let matrices = match light.kind {
+                _ if !light.casts_shadows => Vec::new(),
+                LightKind::Directional { direction } if !directional_done => {
+                    directional_done = true;
+                    cascades(direction, projection_view, reversed_z, shadow_distance)
+                }
+                LightKind::Point { position, attenuation } if !point_done => {
+                    point_done = true;
+                    cube_faces(position, attenuation.range(light.intensity))
+                }
+                _ => Vec::new(),
+            };
+
+            if matrices.is_empty() {
+                first_views.push(None);
+                continue;
+            }
+            first_views.push(Some(views.len()));
+            for matrix in matrices {
+                let index = views.len();
+                views.push(ShadowView::new(index, matrix));
+            }
+        }
+
+        Self { views, first_views }
+    }
+
+    /// No views at all, for frames where nothing casts shadows.
+    pub fn none(light_count: usize) -> Self {
+        Self {
+            views: Vec::new(),
+            first_views: vec![None; light_count],
+        }
+    }
+}
+
+/// Splits the view frustum in `SHADOW_CASCADES` slices, each one covered by an
+/// orthographic projection along `direction`. The far plane may be at infinity, like
+/// the one of `infinite_perspective`, and then the frustum ends `shadow_distance` past
+/// its near plane.
+fn cascades(
+    direction: Vector3<f32>,
+    projection_view: &Matrix4<f32>,
+    reversed_z: bool,
+    shadow_distance: f32,
+) -> Vec<Matrix4<f32>> {
+    let inverse = match projection_view.try_inverse() {
+        Some(inverse) => inverse,
+        None => return Vec::new(),
+    };
+    let unproject = |x: f32, y: f32, z: f32| {
+        Point3::from_homogeneous(inverse * Vector4::new(x, y, z, 1.0))
+            .and_then(|point| if point.coords.iter().all(|v| v.is_finite()) { Some(point) } else { None })
+    };
+
+    // Depths of the near and far planes in normalized device coordinates. Halfway
+    // between them is still a finite distance when the far plane isn't.
+    let (near_z, far_z) = if reversed_z { (1.0, 0.0) } else { (-1.0, 1.0) };
+    let middle_z = (near_z + far_z) / 2.0;
+
+    // How far along the view the cascades reach, in steps from the near plane to the
+    // halfway depth. Every edge of the frustum crosses the same depth in a step, so
+    // they all get extended by the same amount of steps.
+    let (near, middle) = match (unproject(0.0, 0.0, near_z), unproject(0.0, 0.0, middle_z)) {
+        (Some(near), Some(middle)) => (near, middle),
+        _ => return Vec::new(),
+    };
+    let step = distance(&near, &middle);
+    if step <= 0.0 {
+        return Vec::new();
+    }
+    let length = match unproject(0.0, 0.0, far_z) {
+        Some(far) => distance(&near, &far),
+        None => shadow_distance,
+    };
+    let reach = length / step;
+
+    // Each of the four edges from the near plane to where the cascades end.
+    let mut edges = Vec::new();
+    for &(x, y) in &[(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+        match (unproject(x, y, near_z), unproject(x, y, middle_z)) {
+            (Some(near), Some(middle)) => edges.push((near, near + (middle - near) * reach)),
+            _ => return Vec::new(),
+        }
+    }
+
+    // With a perspective projection the near plane is smaller than the far end by
+    // near / far. An orthographic one gives 1, which turns into uniform splits.
+    let near_width = distance(&edges[0].0, &edges[1].0);
+    let far_width = distance(&edges[0].1, &edges[1].1);
+    let ratio = if far_width > 0.0 { near_width / far_width } else { 1.0 };
+
+    let split = |i: usize| {
+        let uniform = i as f32 / SHADOW_CASCADES as f32;
+        if (1.0 - ratio).abs() < 1e-4 || ratio <= 0.0 {
+            return uniform;
+        }
+        let logarithmic = ((1.0 / ratio).powf(uniform) - 1.0) / (1.0 / ratio - 1.0);
+        CASCADE_SPLIT_LAMBDA * logarithmic + (1.0 - CASCADE_SPLIT_LAMBDA) * uniform
+    };
+
+    let direction = direction.normalize();
+    let up = if direction.x.abs() < 1e-3 && direction.z.abs() < 1e-3 {
+        Vector3::z()
+    } else {
+        Vector3::y()
+    };
+
+    (0..SHADOW_CASCADES)
+        .map(|i| {
+            let (start, end) = (split(i), split(i + 1));
+            let corners: Vec<Point3<f32>> = edges
+                .iter()
+                .flat_map(|&(near, far)| vec![near + (far - near) * start, near + (far - near) * end])
+                .collect();
+
+            // A bounding sphere keeps the projection the same size as the camera turns.
+            let center = corners
+                .iter()
+                .fold(Vector3::zeros(), |sum, corner| sum + corner.coords)
+                / corners.len() as f32;
+            let center = Point3::from_coordinates(center);
+            let radius = corners
+                .iter()
+                .map(|corner| distance(&center, corner))
+                .fold(0.0f32, f32::max)
+                .max(1e-3);
+
+            // Things behind the slice can still cast shadows into it.
+            let eye = center - direction * radius * 2.0;
+            let view = Matrix4::look_at_rh(&eye, &center, &up);
+            let projection = Orthographic3::new(-radius, radius, -radius, radius, 0.0, radius * 3.0);
+            projection.to_homogeneous() * view
+        })
+        .collect()
+}
+
+/// One 90 degrees perspective projection for each side of a cube around `position`,
+/// in the order +X, -X, +Y, -Y, +Z, -Z.
+fn cube_faces(position: Point3<f32>, range: f32) -> Vec<Matrix4<f32>> {
+    let projection = Perspective3::new(1.0, FRAC_PI_2, POINT_SHADOW_NEAR, range.max(POINT_SHADOW_NEAR * 2.0));
+    let faces = [
+        (Vector3::x(), -Vector3::y()),
+        (-Vector3::x(), -Vector3::y()),
+        (Vector3::y(), Vector3::z()),
+        (-Vector3::y(), -Vector3::z()),
+        (Vector3::z(), -Vector3::y()),
+        (-Vector3::z(), -Vector3::y()),
+    ];
+    faces
+        .iter()
+        .map(|&(direction, up)| {
+            let view = Matrix4::look_at_rh(&position, &(position + direction), &up);
+            projection.to_homogeneous() * view
+        })
+        .collect()
+}
+
+/// The depth atlas every shadow view is drawn into, and what's needed to draw it.
+pub struct ShadowMap {
+    pub pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    /// Per draw sets laid out for `pipeline`, whose set 0 is only seen by the vertex
+    /// shader unlike the one of the main pass.
+    pub draw_sets: FixedSizeDescriptorSetsPool<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    pub framebuffer: Arc<FramebufferAbstract + Send + Sync>,
+    pub image: Arc<AttachmentImage>,
+    pub sampler: Arc<Sampler>,
+}
+
+impl ShadowMap {
+    pub fn new(device: &Arc<Device>) -> Result<Self, VulkanBackendError> {
+        let vs = shaders::shadow_vs::Shader::load(device.clone()).map_err(VulkanBackendError::ShaderLoading)?;
+        let fs = shaders::shadow_fs::Shader::load(device.clone()).map_err(VulkanBackendError::ShaderLoading)?;
+
+        let render_pass = Arc::new(
+            single_pass_renderpass!(device.clone(),
+            attachments: {
+                depth: {
+                    load: Clear,
+                    store: Store,
+                    format: SHADOW_FORMAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [],
+                depth_stencil: {depth}
+            }
+        )?,
+        ) as Arc<RenderPassAbstract + Send + Sync>;
+
+        let pipeline = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .depth_stencil_simple_depth()
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+                .build(device.clone())?,
+        ) as Arc<GraphicsPipelineAbstract + Send + Sync>;
+
+        let draw_sets = FixedSizeDescriptorSetsPool::new(pipeline.clone(), 0);
+
+        let atlas_size = SHADOW_TILE_SIZE * ATLAS_COLUMNS;
+        let image = AttachmentImage::sampled(device.clone(), [atlas_size, atlas_size], SHADOW_FORMAT)?;
+        let framebuffer = Arc::new(Framebuffer::start(render_pass).add(image.clone())?.build()?);
+
+        // Filtering is done by hand in the shader, comparing each texel.
+        let sampler = Sampler::new(
+            device.clone(),
+            Filter::Nearest,
+            Filter::Nearest,
+            MipmapMode::Nearest,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        )?;
+
+        Ok(Self {
+            pipeline,
+            draw_sets,
+            framebuffer,
+            image,
+            sampler,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use projection::infinite_perspective;
+
+    fn eye() -> Point3<f32> {
+        Point3::new(0.0, 2.0, 5.0)
+    }
+
+    fn view() -> Matrix4<f32> {
+        Matrix4::look_at_rh(&eye(), &Point3::origin(), &Vector3::y())
+    }
+
+    fn camera() -> Matrix4<f32> {
+        let projection = Perspective3::new(16.0 / 9.0, FRAC_PI_2 / 1.5, 0.1, 100.0);
+        projection.to_homogeneous() * view()
+    }
+
+    fn inside(matrix: &Matrix4<f32>, point: &Point3<f32>) -> bool {
+        let clip = matrix * point.to_homogeneous();
+        let ndc = clip.xyz() / clip.w;
+        ndc.iter().all(|v| v.abs() <= 1.0 + 1e-4)
+    }
+
+    // Points along the middle of the view, from the near plane to `distance`.
+    fn points_ahead(distance: f32) -> Vec<Point3<f32>> {
+        let forward = (Point3::origin() - eye()).normalize();
+        (0..21)
+            .map(|i| eye() + forward * (0.1 + (distance - 0.1) * i as f32 / 20.0))
+            .collect()
+    }
+
+    fn assert_covered(matrices: &[Matrix4<f32>], points: &[Point3<f32>]) {
+        for point in points {
+            assert!(
+                matrices.iter().any(|matrix| inside(matrix, point)),
+                "{:?} isn't in any cascade",
+                point
+            );
+        }
+    }
+
+    #[test]
+    fn cascades_cover_the_view_frustum() {
+        let matrices = cascades(Vector3::new(-1.0, -1.0, -0.5), &camera(), false, 1000.0);
+        assert_eq!(matrices.len(), SHADOW_CASCADES);
+        assert_covered(&matrices, &points_ahead(100.0));
+    }
+
+    #[test]
+    fn infinite_cascades_stop_at_the_shadow_distance() {
+        let projection_view = infinite_perspective(16.0 / 9.0, FRAC_PI_2 / 1.5, 0.1, false) * view();
+        let matrices = cascades(Vector3::new(0.0, -1.0, 0.0), &projection_view, false, 20.0);
+        assert_covered(&matrices, &points_ahead(20.0));
+
+        let beyond = eye() + (Point3::origin() - eye()).normalize() * 80.0;
+        assert!(!matrices.iter().any(|matrix| inside(matrix, &beyond)));
+    }
+
+    #[test]
+    fn shadow_distance_leaves_finite_projections_alone() {
+        let direction = Vector3::new(-1.0, -1.0, -0.5);
+        let matrices = cascades(direction, &camera(), false, 100.0);
+        assert_eq!(cascades(direction, &camera(), false, 20.0), matrices);
+        assert_eq!(cascades(direction, &camera(), false, 1000.0), matrices);
+    }
+
+    #[test]
+    fn cascades_of_an_infinite_projection() {
+        for &reversed_z in &[false, true] {
+            let projection_view = infinite_perspective(16.0 / 9.0, FRAC_PI_2 / 1.5, 0.1, reversed_z) * view();
+            let matrices = cascades(Vector3::new(-1.0, -1.0, -0.5), &projection_view, reversed_z, 50.0);
+            assert_eq!(matrices.len(), SHADOW_CASCADES);
+            assert!(matrices.iter().all(|matrix| matrix.iter().all(|v| v.is_finite())));
+            assert_covered(&matrices, &points_ahead(50.0));
+        }
+    }
+
+    #[test]
+    fn first_cascade_is_the_smallest() {
+        let matrices = cascades(Vector3::new(0.0, -1.0, 0.0), &camera(), false, 1000.0);
+        // An orthographic projection scales by one over the half size of the box.
+        let scale = |matrix: &Matrix4<f32>| matrix[(0, 0)].abs();
+        assert!(scale(&matrices[0]) > scale(&matrices[1]));
+        assert!(scale(&matrices[1]) > scale(&matrices[2]));
+    }
+
+    #[test]
+    fn cascades_of_a_singular_projection_are_empty() {
+        assert!(cascades(Vector3::new(0.0, -1.0, 0.0), &Matrix4::zeros(), false, 1000.0).is_empty());
+    }
+
+    #[test]
+    fn views_of_the_first_shadow_casters() {
+        let lights = [
+            Light::directional(Vector3::new(0.0, -1.0, 0.0), [1.0; 3]).with_shadows(true),
+            Light::directional(Vector3::new(1.0, -1.0, 0.0), [1.0; 3]).with_shadows(true),
+            Light::point(Point3::new(0.0, 1.0, 0.0), [1.0; 3]),
+            Light::point(Point3::new(0.0, 2.0, 0.0), [1.0; 3]).with_shadows(true),
+        ];
+        let shadows = ShadowViews::new(&lights, &camera(), false, 100.0);
+        assert_eq!(shadows.views.len(), SHADOW_CASCADES + CUBE_FACES);
+        assert_eq!(shadows.first_views, vec![Some(0), None, None, Some(SHADOW_CASCADES)]);
+    }
+
+    #[test]
+    fn no_views_keeps_a_slot_per_light() {
+        let shadows = ShadowViews::none(3);
+        assert!(shadows.views.is_empty());
+        assert_eq!(shadows.first_views, vec![None, None, None]);
+    }
+}
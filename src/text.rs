@@ -0,0 +1,389 @@
+use overlay::OverlayQueue;
+use texture::{TextureFilter, TextureHandle, TextureOptions, TextureQueue, TextureWrap};
+
+use image::{imageops, Rgba, RgbaImage};
+
+use rusttype::{point, Font, Scale};
+
+use std::collections::HashMap;
+use std::io;
+
+/// Width and height in pixels of each glyph atlas page.
+const ATLAS_SIZE: u32 = 1024;
+
+/// Pages a font may fill before its glyphs are dropped and rasterized again as
+/// they're used, so text at ever changing sizes doesn't grow without bound.
+const MAX_ATLAS_PAGES: usize = 4;
+
+// Empty pixels around each glyph, so filtering doesn't bleed into its neighbours.
+const GLYPH_PADDING: u32 = 1;
+
+/// Refers to a loaded font. Obtained from `VulkanBackend::load_font`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FontHandle(u64);
+
+/// Where the text goes relative to the position it's drawn at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextAlign {
+    /// The position is the top left corner of the text.
+    Left,
+    /// The position is the middle of the top edge of the text.
+    Center,
+    /// The position is the top right corner of the text.
+    Right,
+}
+
+impl Default for TextAlign {
+    fn default() -> Self {
+        TextAlign::Left
+    }
+}
+
+/// Where a glyph is in its atlas and how it sits on the baseline, in pixels.
+#[derive(Debug, Clone, Copy)]
+struct Glyph {
+    advance: f32,
+    // None for glyphs without pixels, like spaces.
+    bitmap: Option<GlyphBitmap>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GlyphBitmap {
+    page: usize,
+    // From the pen position on the baseline to the top left corner.
+    offset: (f32, f32),
+    size: (f32, f32),
+    uv_min: (f32, f32),
+    uv_max: (f32, f32),
+}
+
+/// One texture worth of glyphs. White pixels with the coverage in the alpha channel,
+/// so the overlay color tints them.
+struct AtlasPage {
+    image: RgbaImage,
+    texture: TextureHandle,
+    // Shelf packing: glyphs are placed left to right in rows.
+    cursor: (u32, u32),
+    row_height: u32,
+    // Min and max corners of the pixels changed since the last upload.
+    dirty: Option<((u32, u32), (u32, u32))>,
+}
+
+impl AtlasPage {
+    fn new(texture: TextureHandle) -> Self {
+        Self {
+            image: RgbaImage::from_pixel(ATLAS_SIZE, ATLAS_SIZE, Rgba([255, 255, 255, 0])),
+            texture,
+            cursor: (0, 0),
+            row_height: 0,
+            dirty: None,
+        }
+    }
+
+    /// Finds room for a bitmap of the given size, or `None` when the page is full.
+    fn allocate(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        let (padded_width, padded_height) = (width + GLYPH_PADDING, height + GLYPH_PADDING);
+        if self.cursor.0 + padded_width > ATLAS_SIZE {
+            self.cursor = (0, self.cursor.1 + self.row_height);
+            self.row_height = 0;
+        }
+        if self.cursor.0 + padded_width > ATLAS_SIZE || self.cursor.1 + padded_height > ATLAS_SIZE {
+            return None;
+        }
+        let position = self.cursor;
+        self.cursor.0 += padded_width;
+        self.row_height = self.row_height.max(padded_height);
+        Some(position)
+    }
+
+    fn mark_dirty(&mut self, (x, y): (u32, u32), (width, height): (u32, u32)) {
+        let (min, max) = self.dirty.unwrap_or(((x, y), (x, y)));
+        self.dirty = Some((
+            (min.0.min(x), min.1.min(y)),
+            (max.0.max(x + width), max.1.max(y + height)),
+        ));
+    }
+}
+
+/// The glyphs of a font at every pixel size it has been drawn with, rasterized as
+/// they're first used and packed into as many pages as they need.
+#[derive(Default)]
+struct GlyphAtlas {
+    pages: Vec<AtlasPage>,
+    glyphs: HashMap<(char, u32), Glyph>,
+    // The frame the pages went over `MAX_ATLAS_PAGES`. They're dropped once text
+    // queued in that frame no longer refers to them.
+    overflowed_in: Option<u64>,
+}
+
+impl GlyphAtlas {
+    fn glyph(&mut self, textures: &mut TextureQueue, font: &Font<'static>, pixels: u32, frame: u64, c: char) -> Glyph {
+        if let Some(glyph) = self.glyphs.get(&(c, pixels)) {
+            return *glyph;
+        }
+
+        let scaled = font.glyph(c).scaled(Scale::uniform(pixels as f32));
+        let advance = scaled.h_metrics().advance_width;
+        let positioned = scaled.positioned(point(0.0, 0.0));
+        let bitmap = positioned.pixel_bounding_box().and_then(|bounds| {
+            let (width, height) = (bounds.width() as u32, bounds.height() as u32);
+            let (page, x, y) = self.allocate(textures, frame, width, height)?;
+            {
+                let page = &mut self.pages[page];
+                {
+                    let image = &mut page.image;
+                    positioned.draw(|gx, gy, coverage| {
+                        let alpha = (coverage * 255.0).round() as u8;
+                        image.put_pixel(x + gx, y + gy, Rgba([255, 255, 255, alpha]));
+                    });
+                }
+                page.mark_dirty((x, y), (width, height));
+            }
+            let size = ATLAS_SIZE as f32;
+            Some(GlyphBitmap {
+                page,
+                offset: (bounds.min.x as f32, bounds.min.y as f32),
+                size: (width as f32, height as f32),
+                uv_min: (x as f32 / size, y as f32 / size),
+                uv_max: ((x + width) as f32 / size, (y + height) as f32 / size),
+            })
+        });
+
+        let glyph = Glyph { advance, bitmap };
+        self.glyphs.insert((c, pixels), glyph);
+        glyph
+    }
+
+    /// Finds room for a bitmap of the given size as the page and position in it,
+    /// starting a new page when the last one is full. `None` only for bitmaps bigger
+    /// than a page.
+    fn allocate(
+        &mut self,
+        textures: &mut TextureQueue,
+        frame: u64,
+        width: u32,
+        height: u32,
+    ) -> Option<(usize, u32, u32)> {
+        if width + GLYPH_PADDING > ATLAS_SIZE || height + GLYPH_PADDING > ATLAS_SIZE {
+            warn!(
+                "A {}x{} glyph doesn't fit in the glyph atlas, it won't be drawn",
+                width, height
+            );
+            return None;
+        }
+        if let Some((x, y)) = self.pages.last_mut().and_then(|page| page.allocate(width, height)) {
+            return Some((self.pages.len() - 1, x, y));
+        }
+
+        if self.pages.len() == MAX_ATLAS_PAGES && self.overflowed_in.is_none() {
+            debug!("Glyph atlas went over {} pages, it will start over", MAX_ATLAS_PAGES);
+            self.overflowed_in = Some(frame);
+        }
+        let options = TextureOptions {
+            filter: TextureFilter::Linear,
+            wrap: TextureWrap::ClampToEdge,
+        };
+        let mut page = AtlasPage::new(textures.create_dynamic([ATLAS_SIZE, ATLAS_SIZE], options));
+        let (x, y) = page.allocate(width, height)?;
+        self.pages.push(page);
+        Some((self.pages.len() - 1, x, y))
+    }
+
+    /// Drops the pages and glyphs if they went over `MAX_ATLAS_PAGES` before `frame`.
+    fn trim(&mut self, textures: &mut TextureQueue, frame: u64) {
+        match self.overflowed_in {
+            Some(overflowed_in) if overflowed_in < frame => (),
+            _ => return,
+        }
+        for page in self.pages.drain(..) {
+            textures.release(page.texture);
+        }
+        self.glyphs.clear();
+        self.overflowed_in = None;
+    }
+}
+
+/// The loaded fonts and the atlas of each one that text has been drawn with.
+#[derive(Default)]
+pub struct FontStore {
+    next_handle: u64,
+    fonts: HashMap<FontHandle, Font<'static>>,
+    atlases: HashMap<FontHandle, GlyphAtlas>,
+    // Counts calls to `upload_atlases`, which happen once per frame.
+    frame: u64,
+}
+
+impl FontStore {
+    pub fn load(&mut self, bytes: Vec<u8>) -> io::Result<FontHandle> {
+        let font = Font::from_bytes(bytes).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        let handle = FontHandle(self.next_handle);
+        self.next_handle += 1;
+        self.fonts.insert(handle, font);
+        Ok(handle)
+    }
+
+    /// Forgets the font. Its atlas pages are released with the textures.
+    pub fn release(&mut self, font: FontHandle, textures: &mut TextureQueue) {
+        self.fonts.remove(&font);
+        if let Some(atlas) = self.atlases.remove(&font) {
+            for page in atlas.pages {
+                textures.release(page.texture);
+            }
+        }
+    }
+
+    /// Lays out `text` and queues a quad for each visible glyph. Lines are split at
+    /// `\n` and aligned independently.
+    #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+    pub fn draw(
+        &mut self,
+        font: FontHandle,
+        textures: &mut TextureQueue,
+        overlay: &mut OverlayQueue,
+        position: (f32, f32),
+        size: f32,
+        color: [f32; 4],
+        align: TextAlign,
+        text: &str,
+    ) {
+        let font_data = match self.fonts.get(&font) {
+            Some(font_data) => font_data,
+            None => {
+                warn!("Tried to draw text with {:?}, which isn't loaded", font);
+                return;
+            }
+        };
+        // Glyphs are rasterized at the size they're shown, so each size has its own
+        // glyphs in the atlas.
+        let pixels = size.round().max(1.0) as u32;
+        let scale = Scale::uniform(pixels as f32);
+        let frame = self.frame;
+        let atlas = self.atlases.entry(font).or_insert_with(GlyphAtlas::default);
+        atlas.trim(textures, frame);
+
+        let v_metrics = font_data.v_metrics(scale);
+        let line_height = v_metrics.ascent - v_metrics.descent + v_metrics.line_gap;
+
+        for (i, line) in text.lines().enumerate() {
+            let mut glyphs = Vec::new();
+            let mut pen = 0.0;
+            let mut previous = None;
+            for c in line.chars() {
+                if let Some(previous) = previous {
+                    pen += font_data.pair_kerning(scale, previous, c);
+                }
+                let glyph = atlas.glyph(textures, font_data, pixels, frame, c);
+                glyphs.push((pen, glyph));
+                pen += glyph.advance;
+                previous = Some(c);
+            }
+
+            let start_x = match align {
+                TextAlign::Left => position.0,
+                TextAlign::Center => position.0 - pen / 2.0,
+                TextAlign::Right => position.0 - pen,
+            };
+            let baseline = position.1 + v_metrics.ascent + line_height * i as f32;
+
+            for (x, glyph) in glyphs {
+                if let Some(bitmap) = glyph.bitmap {
+                    overlay.quad(
+                        (start_x + x + bitmap.offset.0, baseline + bitmap.offset.1),
+                        bitmap.size,
+                        bitmap.uv_min,
+                        bitmap.uv_max,
+                        color,
+                        Some(atlas.pages[bitmap.page].texture),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Queues the pixels of the glyphs rasterized since the last call to be copied
+    /// into the atlas pages. Their handles stay the same, so text queued earlier in the
+    /// frame shows the new glyphs too.
+    pub fn upload_atlases(&mut self, textures: &mut TextureQueue) {
+        for atlas in self.atlases.values_mut() {
+            for page in &mut atlas.pages {
+                if let Some((min, max)) = page.dirty.take() {
+                    let region = imageops::crop(&mut page.image, min.0, min.1, max.0 - min.0, max.1 - min.1).to_image();
+                    textures.update(page.texture, [min.0, min.1], region);
+                }
+            }
+        }
+        self.frame += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_packs_glyphs_in_rows() {
+        let mut page = AtlasPage::new(TextureQueue::default().create_dynamic([1, 1], TextureOptions::default()));
+        assert_eq!(page.allocate(10, 20), Some((0, 0)));
+        assert_eq!(page.allocate(10, 5), Some((10 + GLYPH_PADDING, 0)));
+        assert_eq!(
+            page.allocate(ATLAS_SIZE - GLYPH_PADDING, 5),
+            Some((0, 20 + GLYPH_PADDING))
+        );
+    }
+
+    #[test]
+    fn full_page_allocates_nothing() {
+        let mut page = AtlasPage::new(TextureQueue::default().create_dynamic([1, 1], TextureOptions::default()));
+        let side = ATLAS_SIZE - GLYPH_PADDING;
+        assert_eq!(page.allocate(side, side), Some((0, 0)));
+        assert_eq!(page.allocate(1, 1), None);
+    }
+
+    #[test]
+    fn full_atlas_starts_a_new_page() {
+        let mut textures = TextureQueue::default();
+        let mut atlas = GlyphAtlas::default();
+        let side = ATLAS_SIZE - GLYPH_PADDING;
+        assert_eq!(atlas.allocate(&mut textures, 0, side, side), Some((0, 0, 0)));
+        assert_eq!(atlas.allocate(&mut textures, 0, 8, 8), Some((1, 0, 0)));
+        assert_eq!(atlas.pages.len(), 2);
+        assert_eq!(textures.dynamic.len(), 2);
+        assert_ne!(atlas.pages[0].texture, atlas.pages[1].texture);
+    }
+
+    #[test]
+    fn oversized_glyphs_are_not_allocated() {
+        let mut textures = TextureQueue::default();
+        let mut atlas = GlyphAtlas::default();
+        assert_eq!(atlas.allocate(&mut textures, 0, ATLAS_SIZE, 1), None);
+        assert!(atlas.pages.is_empty());
+    }
+
+    #[test]
+    fn overflowing_pages_are_dropped_in_a_later_frame() {
+        let mut textures = TextureQueue::default();
+        let mut atlas = GlyphAtlas::default();
+        let side = ATLAS_SIZE - GLYPH_PADDING;
+        for _ in 0..MAX_ATLAS_PAGES + 1 {
+            atlas.allocate(&mut textures, 3, side, side);
+        }
+        assert_eq!(atlas.pages.len(), MAX_ATLAS_PAGES + 1);
+        assert_eq!(atlas.overflowed_in, Some(3));
+
+        atlas.trim(&mut textures, 3);
+        assert_eq!(atlas.pages.len(), MAX_ATLAS_PAGES + 1);
+
+        atlas.trim(&mut textures, 4);
+        assert!(atlas.pages.is_empty());
+        assert_eq!(textures.releases.len(), MAX_ATLAS_PAGES + 1);
+        assert_eq!(atlas.overflowed_in, None);
+    }
+
+    #[test]
+    fn dirty_region_covers_every_glyph() {
+        let mut page = AtlasPage::new(TextureQueue::default().create_dynamic([1, 1], TextureOptions::default()));
+        page.mark_dirty((10, 4), (5, 6));
+        page.mark_dirty((2, 12), (3, 3));
+        assert_eq!(page.dirty, Some(((2, 4), (15, 15))));
+    }
+}
@@ -0,0 +1,388 @@
+use error::VulkanBackendError;
+
+use image::RgbaImage;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::DescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::ClearValue;
+use vulkano::format::Format;
+use vulkano::image::Dimensions;
+use vulkano::image::ImageUsage;
+use vulkano::image::ImageViewAccess;
+use vulkano::image::ImmutableImage;
+use vulkano::image::StorageImage;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::sampler::Filter;
+use vulkano::sampler::MipmapMode;
+use vulkano::sampler::Sampler;
+use vulkano::sampler::SamplerAddressMode;
+use vulkano::sync::GpuFuture;
+
+/// Descriptor set the fragment shader samples the texture from.
+pub const TEXTURE_SET: usize = 1;
+
+/// Refers to an image that lives on the GPU. Obtained from `VulkanBackend::create_texture`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureHandle(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextureFilter {
+    Nearest,
+    Linear,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextureWrap {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+}
+
+/// How a texture is sampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureOptions {
+    pub filter: TextureFilter,
+    pub wrap: TextureWrap,
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        Self {
+            filter: TextureFilter::Linear,
+            wrap: TextureWrap::Repeat,
+        }
+    }
+}
+
+/// CPU side bookkeeping of the textures, applied by the renderer at the start of the
+/// next frame like `mesh::MeshQueue`.
+#[derive(Default)]
+pub struct TextureQueue {
+    next_handle: u64,
+    pub uploads: Vec<(TextureHandle, RgbaImage, TextureOptions)>,
+    pub dynamic: Vec<(TextureHandle, [u32; 2], TextureOptions)>,
+    pub updates: Vec<(TextureHandle, [u32; 2], RgbaImage)>,
+    pub releases: Vec<TextureHandle>,
+}
+
+impl TextureQueue {
+    pub fn upload(&mut self, image: RgbaImage, options: TextureOptions) -> TextureHandle {
+        let handle = self.next_handle();
+        self.uploads.push((handle, image, options));
+        handle
+    }
+
+    /// A texture of the given width and height that starts out transparent white and
+    /// is filled in piece by piece with `update`.
+    pub fn create_dynamic(&mut self, dimensions: [u32; 2], options: TextureOptions) -> TextureHandle {
+        let handle = self.next_handle();
+        self.dynamic.push((handle, dimensions, options));
+        handle
+    }
+
+    /// Overwrites the pixels of a texture from `create_dynamic`, with the top left
+    /// corner of `image` at `offset`.
+    pub fn update(&mut self, handle: TextureHandle, offset: [u32; 2], image: RgbaImage) {
+        self.updates.push((handle, offset, image));
+    }
+
+    pub fn release(&mut self, handle: TextureHandle) {
+        self.releases.push(handle);
+    }
+
+    fn next_handle(&mut self) -> TextureHandle {
+        let handle = TextureHandle(self.next_handle);
+        self.next_handle += 1;
+        handle
+    }
+}
+
+/// Descriptor set the overlay fragment shader samples the texture from.
+pub const OVERLAY_TEXTURE_SET: usize = 0;
+
+/// The sets a texture is bound through, one allocated from the layout of each
+/// pipeline that samples it.
+#[derive(Clone)]
+struct TextureSets {
+    main: Arc<DescriptorSet + Send + Sync>,
+    overlay: Arc<DescriptorSet + Send + Sync>,
+}
+
+/// The images behind the texture handles, each one already bound to descriptor sets
+/// along with its sampler.
+pub struct TextureStore {
+    pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    overlay_pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    samplers: HashMap<TextureOptions, Arc<Sampler>>,
+    sets: HashMap<TextureHandle, TextureSets>,
+    // The images of the textures from `TextureQueue::create_dynamic`, to copy updates into.
+    dynamic: HashMap<TextureHandle, Arc<StorageImage<Format>>>,
+    // Bound when a draw has no texture, so the shader doesn't need a separate path.
+    white: TextureSets,
+}
+
+impl TextureStore {
+    pub fn new(
+        queue: &Arc<Queue>,
+        pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+        overlay_pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    ) -> Result<Self, VulkanBackendError> {
+        let mut samplers = HashMap::new();
+        let white_pixel = RgbaImage::from_raw(1, 1, vec![255, 255, 255, 255]).unwrap();
+        let (white, future) = upload(
+            queue,
+            (&pipeline, &overlay_pipeline),
+            &mut samplers,
+            white_pixel,
+            TextureOptions::default(),
+        )?;
+        future.then_signal_fence_and_flush()?.wait(None)?;
+
+        Ok(Self {
+            pipeline,
+            overlay_pipeline,
+            samplers,
+            sets: HashMap::new(),
+            dynamic: HashMap::new(),
+            white,
+        })
+    }
+
+    /// Applies the queued uploads, updates and releases, after `previous_frame_end`.
+    /// The returned future must be waited on before any of the new textures are
+    /// sampled.
+    pub fn sync(
+        &mut self,
+        queue: &Arc<Queue>,
+        pending: &mut TextureQueue,
+        previous_frame_end: Box<GpuFuture>,
+    ) -> Result<Box<GpuFuture>, VulkanBackendError> {
+        let mut future = previous_frame_end;
+
+        for (handle, image, options) in pending.uploads.drain(..) {
+            let pipelines = (&self.pipeline, &self.overlay_pipeline);
+            let (sets, upload_future) = upload(queue, pipelines, &mut self.samplers, image, options)?;
+            self.sets.insert(handle, sets);
+            future = Box::new(future.join(upload_future));
+        }
+
+        // New dynamic images are cleared and then updated in place, all in one command
+        // buffer, so only the changed pixels go through the copy. Frames in flight may
+        // still sample the images, so the copy waits for them.
+        if !pending.dynamic.is_empty() || !pending.updates.is_empty() {
+            let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(queue.device().clone(), queue.family())
+                .map_err(VulkanBackendError::CommandBufferCreation)?;
+
+            for (handle, dimensions, options) in pending.dynamic.drain(..) {
+                let image = StorageImage::with_usage(
+                    queue.device().clone(),
+                    Dimensions::Dim2d {
+                        width: dimensions[0],
+                        height: dimensions[1],
+                    },
+                    Format::R8G8B8A8Srgb,
+                    ImageUsage {
+                        transfer_destination: true,
+                        sampled: true,
+                        ..ImageUsage::none()
+                    },
+                    Some(queue.family()),
+                )?;
+                let pipelines = (&self.pipeline, &self.overlay_pipeline);
+                let sets = bind(queue, pipelines, &mut self.samplers, image.clone(), options)?;
+                builder = builder.clear_color_image(image.clone(), ClearValue::Float([1.0, 1.0, 1.0, 0.0]))?;
+                self.sets.insert(handle, sets);
+                self.dynamic.insert(handle, image);
+            }
+
+            for (handle, offset, image) in pending.updates.drain(..) {
+                let destination = match self.dynamic.get(&handle) {
+                    Some(destination) => destination.clone(),
+                    None => {
+                        warn!("Tried to update {:?}, which isn't a dynamic texture", handle);
+                        continue;
+                    }
+                };
+                let (width, height) = image.dimensions();
+                let source = CpuAccessibleBuffer::from_iter(
+                    queue.device().clone(),
+                    BufferUsage::transfer_source(),
+                    image.into_raw().into_iter(),
+                )?;
+                builder = builder.copy_buffer_to_image_dimensions(
+                    source,
+                    destination,
+                    [offset[0], offset[1], 0],
+                    [width, height, 1],
+                    0,
+                    1,
+                    0,
+                )?;
+            }
+
+            future = Box::new(future.then_execute(queue.clone(), builder.build()?)?);
+        }
+
+        for handle in pending.releases.drain(..) {
+            self.sets.remove(&handle);
+            self.dynamic.remove(&handle);
+        }
+
+        Ok(future)
+    }
+
+    /// The set to bind for a draw. Unknown handles and `None` sample plain white.
+    pub fn set(&self, texture: Option<TextureHandle>) -> Arc<DescriptorSet + Send + Sync> {
+        self.sets(texture).main.clone()
+    }
+
+    /// Like `set`, for the overlay pipeline.
+    pub fn overlay_set(&self, texture: Option<TextureHandle>) -> Arc<DescriptorSet + Send + Sync> {
+        self.sets(texture).overlay.clone()
+    }
+
+    #[cfg(test)]
+    pub fn dynamic_image(&self, texture: TextureHandle) -> Option<Arc<StorageImage<Format>>> {
+        self.dynamic.get(&texture).cloned()
+    }
+
+    #[cfg(test)]
+    pub fn sampler_count(&self) -> usize {
+        self.samplers.len()
+    }
+
+    fn sets(&self, texture: Option<TextureHandle>) -> &TextureSets {
+        texture.and_then(|handle| self.sets.get(&handle)).unwrap_or(&self.white)
+    }
+}
+
+fn sampler(
+    queue: &Arc<Queue>,
+    samplers: &mut HashMap<TextureOptions, Arc<Sampler>>,
+    options: TextureOptions,
+) -> Result<Arc<Sampler>, VulkanBackendError> {
+    if let Some(sampler) = samplers.get(&options) {
+        return Ok(sampler.clone());
+    }
+
+    let filter = match options.filter {
+        TextureFilter::Nearest => Filter::Nearest,
+        TextureFilter::Linear => Filter::Linear,
+    };
+    let address_mode = match options.wrap {
+        TextureWrap::Repeat => SamplerAddressMode::Repeat,
+        TextureWrap::MirroredRepeat => SamplerAddressMode::MirroredRepeat,
+        TextureWrap::ClampToEdge => SamplerAddressMode::ClampToEdge,
+    };
+    let sampler = Sampler::new(
+        queue.device().clone(),
+        filter,
+        filter,
+        MipmapMode::Nearest,
+        address_mode,
+        address_mode,
+        address_mode,
+        0.0,
+        1.0,
+        0.0,
+        0.0,
+    )?;
+    samplers.insert(options, sampler.clone());
+    Ok(sampler)
+}
+
+fn upload(
+    queue: &Arc<Queue>,
+    (pipeline, overlay_pipeline): (
+        &Arc<GraphicsPipelineAbstract + Send + Sync>,
+        &Arc<GraphicsPipelineAbstract + Send + Sync>,
+    ),
+    samplers: &mut HashMap<TextureOptions, Arc<Sampler>>,
+    image: RgbaImage,
+    options: TextureOptions,
+) -> Result<(TextureSets, Box<GpuFuture>), VulkanBackendError> {
+    let (width, height) = image.dimensions();
+    let (texture, future) = ImmutableImage::from_iter(
+        image.into_raw().into_iter(),
+        Dimensions::Dim2d { width, height },
+        Format::R8G8B8A8Srgb,
+        queue.clone(),
+    )?;
+
+    let sets = bind(queue, (pipeline, overlay_pipeline), samplers, texture, options)?;
+    Ok((sets, Box::new(future)))
+}
+
+fn bind<I>(
+    queue: &Arc<Queue>,
+    (pipeline, overlay_pipeline): (
+        &Arc<GraphicsPipelineAbstract + Send + Sync>,
+        &Arc<GraphicsPipelineAbstract + Send + Sync>,
+    ),
+    samplers: &mut HashMap<TextureOptions, Arc<Sampler>>,
+    texture: I,
+    options: TextureOptions,
+) -> Result<TextureSets, VulkanBackendError>
+where
+    I: ImageViewAccess + Clone + Send + Sync + 'static,
+{
+    let sampler = sampler(queue, samplers, options)?;
+    Ok(TextureSets {
+        main: Arc::new(
+            PersistentDescriptorSet::start(pipeline.clone(), TEXTURE_SET)
+                .add_sampled_image(texture.clone(), sampler.clone())?
+                .build()?,
+        ),
+        overlay: Arc::new(
+            PersistentDescriptorSet::start(overlay_pipeline.clone(), OVERLAY_TEXTURE_SET)
+                .add_sampled_image(texture, sampler)?
+                .build()?,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uploads_get_their_own_handles() {
+        let mut queue = TextureQueue::default();
+        let a = queue.upload(RgbaImage::new(1, 1), TextureOptions::default());
+        let b = queue.upload(RgbaImage::new(2, 2), TextureOptions::default());
+        assert_ne!(a, b);
+        assert_eq!(
+            queue.uploads.iter().map(|upload| upload.0).collect::<Vec<_>>(),
+            vec![a, b]
+        );
+    }
+
+    #[test]
+    fn dynamic_textures_share_the_handle_sequence() {
+        let mut queue = TextureQueue::default();
+        let uploaded = queue.upload(RgbaImage::new(1, 1), TextureOptions::default());
+        let dynamic = queue.create_dynamic([64, 32], TextureOptions::default());
+        assert_ne!(uploaded, dynamic);
+        assert_eq!(queue.uploads.len(), 1);
+        assert_eq!(queue.dynamic[0].0, dynamic);
+        assert_eq!(queue.dynamic[0].1, [64, 32]);
+    }
+
+    #[test]
+    fn updates_keep_their_offset_and_pixels() {
+        let mut queue = TextureQueue::default();
+        let handle = queue.create_dynamic([64, 64], TextureOptions::default());
+        queue.update(handle, [8, 16], RgbaImage::new(4, 2));
+        let (updated, offset, ref image) = queue.updates[0];
+        assert_eq!(updated, handle);
+        assert_eq!(offset, [8, 16]);
+        assert_eq!(image.dimensions(), (4, 2));
+    }
+}